@@ -0,0 +1,1189 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use rust_decimal::Decimal;
+
+use crate::event_log::EventLog;
+use crate::fees::FeeSchedule;
+use crate::ledger::FeeLedger;
+use crate::model::*;
+use crate::policy::{Policy, TxIdScope};
+use crate::precision::Precision;
+use crate::rates::RateTable;
+use crate::sharded::ShardedEngine;
+use crate::store::{MemoryStore, TransactionStore};
+use crate::tx_index::TxIndex;
+use crate::validation::validate;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// What an accepted transaction did to its account.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    ChargedBack,
+    Deposited,
+    Disputed,
+    Frozen,
+    Resolved,
+    Transferred,
+    Unlocked,
+    Withdrawn,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::ChargedBack => "charged_back",
+            Outcome::Deposited => "deposited",
+            Outcome::Disputed => "disputed",
+            Outcome::Frozen => "frozen",
+            Outcome::Resolved => "resolved",
+            Outcome::Transferred => "transferred",
+            Outcome::Unlocked => "unlocked",
+            Outcome::Withdrawn => "withdrawn",
+        }
+    }
+}
+
+/// Why a transaction was not applied. The account is left untouched.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rejection {
+    /// The account was locked by an earlier chargeback.
+    AccountLocked,
+    /// A dispute referenced a transaction that is already in dispute.
+    AlreadyDisputed,
+    /// Applying the transaction would take a balance past what a `Decimal`
+    /// can hold.
+    BalanceOverflow,
+    /// A dispute referenced a transaction whose dispute was already resolved
+    /// or charged back. Transactions may only be disputed once.
+    DisputeSettled,
+    /// A dispute came more than the dispute window after the transaction it
+    /// referenced.
+    DisputeExpired,
+    /// A transfer's destination account was locked by an earlier chargeback.
+    DestinationLocked,
+    /// A deposit, withdrawal or transfer reused a transaction ID.
+    DuplicateTx,
+    /// The transaction could not be written to the event log, so it was
+    /// not applied either.
+    EventLogFailed,
+    /// A dispute, resolve or chargeback referenced another client's
+    /// transaction. Flagged, since it is more likely abuse than a mistake.
+    ForeignReference,
+    /// A withdrawal asked for more than the available amount.
+    InsufficientFunds,
+    /// A deposit, withdrawal or transfer had no amount.
+    MissingAmount,
+    /// A transfer did not say which client to pay.
+    MissingDestination,
+    /// A freeze or unlock did not say which operator sent it.
+    MissingOperator,
+    /// A deposit or withdrawal had a negative amount.
+    NegativeAmount,
+    /// A dispute referenced a transfer. Transfers move funds between
+    /// clients of this engine and cannot be disputed.
+    NotDisputable,
+    /// A resolve or chargeback referenced a transaction not in dispute.
+    NotDisputed,
+    /// An unlock was sent for an account that is not locked.
+    NotLocked,
+    /// A transaction's timestamp was earlier than the client's previous
+    /// transaction.
+    OutOfOrder,
+    /// A transfer named its own client as the destination.
+    SelfTransfer,
+    /// The transaction store could not be read or written.
+    StoreFailed,
+    /// A deposit or withdrawal was in a currency the rate table has no rate
+    /// for.
+    UnknownRate,
+    /// The amount had more decimal places than the engine keeps, and the
+    /// engine is set to reject rather than round.
+    TooPrecise,
+    /// A deposit, withdrawal or transfer used a transaction ID that already
+    /// belongs to another client.
+    TxIdInUse,
+    /// A dispute, resolve or chargeback carried an amount. They take the
+    /// amount of the transaction they reference.
+    UnexpectedAmount,
+    /// A dispute, resolve or chargeback referenced an unknown transaction.
+    UnknownReference,
+    /// A deposit or withdrawal had an amount of zero.
+    ZeroAmount,
+}
+
+impl Rejection {
+    /// Whether the rejection deserves a closer look than a simple mistake in
+    /// the input.
+    pub fn is_flagged(&self) -> bool {
+        matches!(self, Rejection::ForeignReference)
+    }
+
+    /// Machine-readable reason code used in the rejects report.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::AccountLocked => "account_locked",
+            Rejection::AlreadyDisputed => "already_disputed",
+            Rejection::BalanceOverflow => "balance_overflow",
+            Rejection::DisputeExpired => "dispute_expired",
+            Rejection::DisputeSettled => "dispute_settled",
+            Rejection::DestinationLocked => "destination_locked",
+            Rejection::DuplicateTx => "duplicate_tx",
+            Rejection::EventLogFailed => "event_log_failed",
+            Rejection::ForeignReference => "foreign_reference",
+            Rejection::InsufficientFunds => "insufficient_funds",
+            Rejection::MissingAmount => "missing_amount",
+            Rejection::MissingDestination => "missing_destination",
+            Rejection::MissingOperator => "missing_operator",
+            Rejection::NegativeAmount => "negative_amount",
+            Rejection::NotDisputable => "not_disputable",
+            Rejection::NotDisputed => "not_disputed",
+            Rejection::NotLocked => "not_locked",
+            Rejection::OutOfOrder => "out_of_order",
+            Rejection::SelfTransfer => "self_transfer",
+            Rejection::StoreFailed => "store_failed",
+            Rejection::TooPrecise => "too_precise",
+            Rejection::TxIdInUse => "tx_id_in_use",
+            Rejection::UnexpectedAmount => "unexpected_amount",
+            Rejection::UnknownRate => "unknown_rate",
+            Rejection::UnknownReference => "unknown_reference",
+            Rejection::ZeroAmount => "zero_amount",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::AccountLocked => write!(f, "account locked"),
+            Rejection::AlreadyDisputed => write!(f, "transaction already in dispute"),
+            Rejection::BalanceOverflow => write!(f, "balance overflow"),
+            Rejection::DisputeExpired => write!(f, "dispute window has passed"),
+            Rejection::DisputeSettled => write!(f, "transaction already disputed once"),
+            Rejection::DestinationLocked => write!(f, "destination account locked"),
+            Rejection::DuplicateTx => write!(f, "duplicate transaction"),
+            Rejection::EventLogFailed => write!(f, "event log write failed"),
+            Rejection::ForeignReference => write!(f, "references another client's transaction"),
+            Rejection::InsufficientFunds => write!(f, "insufficient available funds"),
+            Rejection::MissingAmount => write!(f, "missing amount"),
+            Rejection::MissingDestination => write!(f, "missing transfer destination"),
+            Rejection::MissingOperator => write!(f, "missing operator"),
+            Rejection::NegativeAmount => write!(f, "negative amount"),
+            Rejection::NotDisputable => write!(f, "transaction cannot be disputed"),
+            Rejection::NotDisputed => write!(f, "transaction not in dispute"),
+            Rejection::NotLocked => write!(f, "account not locked"),
+            Rejection::OutOfOrder => write!(f, "timestamp before the previous transaction"),
+            Rejection::SelfTransfer => write!(f, "transfer to the same client"),
+            Rejection::StoreFailed => write!(f, "transaction store failed"),
+            Rejection::TooPrecise => write!(f, "amount has too many decimal places"),
+            Rejection::TxIdInUse => write!(f, "transaction ID used by another client"),
+            Rejection::UnexpectedAmount => write!(f, "amount not allowed for this type"),
+            Rejection::UnknownRate => write!(f, "no exchange rate for currency"),
+            Rejection::UnknownReference => write!(f, "referenced transaction not found"),
+            Rejection::ZeroAmount => write!(f, "zero amount"),
+        }
+    }
+}
+
+impl Error for Rejection {}
+
+/// A fee taken from a client's available funds, in the currency of the
+/// transaction that caused it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Charge {
+    pub(crate) amount: Decimal,
+    pub(crate) currency: Option<Currency>,
+}
+
+impl Charge {
+    // No charge at all for a zero fee, so nothing is credited or written to
+    // the fee ledger.
+    fn new(amount: Decimal, currency: Option<Currency>) -> Option<Charge> {
+        if amount.is_zero() {
+            None
+        } else {
+            Some(Charge { amount, currency })
+        }
+    }
+}
+
+// What processing did to the account, and the fee it charged, if any.
+type Processed = (Outcome, Option<Charge>);
+
+// The engine's fee schedule, with fees rounded to the engine's precision.
+struct Fees<'a> {
+    schedule: Option<&'a FeeSchedule>,
+    precision: Precision,
+}
+
+impl Fees<'_> {
+    fn fee(&self, client: u16, kind: TransactionType, amount: Decimal) -> Decimal {
+        match self.schedule {
+            Some(schedule) => {
+                let mut fee = self.precision.round(schedule.fee(client, kind, amount));
+                fee.rescale(self.precision.scale);
+                fee
+            }
+            None => Decimal::ZERO,
+        }
+    }
+}
+
+/// Holds every client account and applies transactions to them one at a
+/// time.
+#[derive(Debug)]
+pub struct PaymentEngine {
+    // Ordered by client so every listing of accounts comes out the same way
+    // for the same input.
+    pub(crate) client_accounts: BTreeMap<u16, ClientAccount>,
+    pub(crate) transactions: Box<dyn TransactionStore>,
+    pub(crate) event_log: Option<EventLog>,
+    pub(crate) precision: Precision,
+    pub(crate) rates: Option<RateTable>,
+    pub(crate) fees: Option<FeeSchedule>,
+    pub(crate) fee_ledger: Option<FeeLedger>,
+    pub(crate) policy: Policy,
+    // Every transaction ID used so far by any client. In a `ShardedEngine`,
+    // each engine's index also holds the IDs of other workers' clients that
+    // its own clients have run into.
+    pub(crate) tx_index: TxIndex,
+}
+
+impl Default for PaymentEngine {
+    fn default() -> PaymentEngine {
+        PaymentEngine::with_store(Box::new(MemoryStore::new()))
+    }
+}
+
+impl PaymentEngine {
+    pub fn new() -> PaymentEngine {
+        PaymentEngine::default()
+    }
+
+    /// Keeps deposits and withdrawals in `transactions` instead of memory.
+    pub fn with_store(transactions: Box<dyn TransactionStore>) -> PaymentEngine {
+        PaymentEngine {
+            client_accounts: BTreeMap::new(),
+            transactions,
+            event_log: None,
+            precision: Precision::default(),
+            rates: None,
+            fees: None,
+            fee_ledger: None,
+            policy: Policy::default(),
+            tx_index: TxIndex::new(),
+        }
+    }
+
+    /// Logs every accepted transaction to `event_log` before applying it.
+    pub fn set_event_log(&mut self, event_log: EventLog) {
+        self.event_log = Some(event_log);
+    }
+
+    /// Sets how many decimal places amounts and balances are kept to, and
+    /// rewrites existing balances at that scale.
+    pub fn set_precision(&mut self, precision: Precision) {
+        self.precision = precision;
+        for account in self.client_accounts.values_mut() {
+            precision.rescale(account);
+        }
+    }
+
+    /// Converts deposits and withdrawals in other currencies into the base
+    /// currency with `rates`, instead of keeping a balance per currency.
+    pub fn set_rates(&mut self, rates: RateTable) {
+        self.rates = Some(rates);
+    }
+
+    /// Charges fees from `fees` on every transaction and credits them to its
+    /// house account.
+    pub fn set_fees(&mut self, fees: FeeSchedule) {
+        self.fees = Some(fees);
+    }
+
+    /// Writes every fee charged to `fee_ledger`.
+    pub fn set_fee_ledger(&mut self, fee_ledger: FeeLedger) {
+        self.fee_ledger = Some(fee_ledger);
+    }
+
+    /// Rejects disputes that come more than `days` days after the transaction
+    /// they reference. Only applies when both carry a timestamp.
+    pub fn set_dispute_window(&mut self, days: u64) {
+        self.policy.dispute_window = Some(days);
+    }
+
+    /// Sets the rules transactions are processed by.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    pub(crate) fn event_log_mut(&mut self) -> Option<&mut EventLog> {
+        self.event_log.as_mut()
+    }
+
+    pub fn fee_ledger_mut(&mut self) -> Option<&mut FeeLedger> {
+        self.fee_ledger.as_mut()
+    }
+
+    pub fn apply(&mut self, transaction: ReadTransaction) -> Result<Outcome, Rejection> {
+        self.apply_with(transaction, None)
+    }
+
+    /// Applies a transfer whose destination client is held by `destination`
+    /// instead of this engine. Used for transfers between shards.
+    pub(crate) fn apply_across(
+        &mut self,
+        destination: &mut PaymentEngine,
+        transaction: ReadTransaction,
+    ) -> Result<Outcome, Rejection> {
+        self.apply_with(transaction, Some(destination))
+    }
+
+    fn apply_with(
+        &mut self,
+        mut transaction: ReadTransaction,
+        destination: Option<&mut PaymentEngine>,
+    ) -> Result<Outcome, Rejection> {
+        validate(&transaction)?;
+
+        // Amounts are brought to the engine's precision before they are
+        // logged, so a replay never depends on how rounding was configured.
+        if let Some(amount) = transaction.amount {
+            match self.precision.normalize(amount) {
+                // Rounding can take a small amount all the way to zero, which
+                // would leave a disputable record of nothing behind.
+                Some(amount) if amount.is_zero() => {
+                    warn!(
+                        "Rejecting transaction. Amount rounds to zero. Transaction ID: {}",
+                        &transaction.tx
+                    );
+                    return Err(Rejection::ZeroAmount);
+                }
+                Some(amount) => transaction.amount = Some(amount),
+                None => {
+                    warn!(
+                        "Rejecting transaction. Amount has more than {} decimal places. Transaction ID: {}",
+                        self.precision.scale, &transaction.tx
+                    );
+                    return Err(Rejection::TooPrecise);
+                }
+            }
+        }
+        // Conversion happens before logging for the same reason, so the log
+        // holds the converted amount and replays without the rate table.
+        let rate = self.convert(&mut transaction)?;
+
+        let mark = match self.event_log.as_mut() {
+            Some(event_log) => match event_log.append(&transaction) {
+                Ok(mark) => Some(mark),
+                Err(err) => {
+                    error!(
+                        "Rejecting transaction. Unable to write event log. Transaction ID: {}. Error: {}",
+                        &transaction.tx, err
+                    );
+                    return Err(Rejection::EventLogFailed);
+                }
+            },
+            None => None,
+        };
+
+        let outcome = self.apply_unlogged(transaction, rate, destination);
+
+        // A failed withdrawal the policy keeps for disputes changed the
+        // transaction store, so it stays in the log for replay to keep too.
+        let rolled_back = match outcome {
+            Err(rejection) => !self.policy.records_failure(transaction.kind, rejection),
+            Ok(_) => false,
+        };
+        if let (true, Some(mark)) = (rolled_back, mark) {
+            if let Err(err) = self.event_log.as_mut().unwrap().rollback(mark) {
+                // The rejected row stays in the log. Replay rejects it again,
+                // so the rebuilt state is still correct.
+                error!(
+                    "Unable to roll back event log. Transaction ID: {}. Error: {}",
+                    &transaction.tx, err
+                );
+            }
+        }
+        outcome
+    }
+
+    // Turns a deposit, withdrawal or transfer in another currency into one in
+    // the base currency when there is a rate table, and returns the rate it
+    // used.
+    fn convert(&self, transaction: &mut ReadTransaction) -> Result<Option<Decimal>, Rejection> {
+        let rates = match &self.rates {
+            Some(rates) => rates,
+            None => return Ok(None),
+        };
+        let (amount, currency) = match (transaction.kind, transaction.amount, transaction.currency)
+        {
+            (
+                TransactionType::Deposit | TransactionType::Transfer | TransactionType::Withdrawal,
+                Some(amount),
+                Some(currency),
+            ) => (amount, currency),
+            _ => return Ok(None),
+        };
+
+        let rate = match rates.rate(transaction.kind, currency) {
+            Some(rate) => rate,
+            None => {
+                info!(
+                    "Rejecting transaction. No exchange rate for {}. Transaction ID: {}",
+                    currency, &transaction.tx
+                );
+                return Err(Rejection::UnknownRate);
+            }
+        };
+        let converted = match amount.checked_mul(rate) {
+            Some(converted) => self.precision.round(converted),
+            None => {
+                error!(
+                    "Rejecting transaction. Converted amount would overflow. Transaction ID: {}",
+                    &transaction.tx
+                );
+                return Err(Rejection::BalanceOverflow);
+            }
+        };
+        if converted.is_zero() {
+            warn!(
+                "Rejecting transaction. Converted amount rounds to zero. Transaction ID: {}",
+                &transaction.tx
+            );
+            return Err(Rejection::ZeroAmount);
+        }
+
+        transaction.amount = Some(converted);
+        transaction.currency = None;
+        Ok(Some(rate))
+    }
+
+    // A transfer's destination account is taken out of whichever engine holds
+    // it while the transfer is applied, so both accounts can be changed
+    // together, and put back afterwards.
+    fn apply_unlogged(
+        &mut self,
+        transaction: ReadTransaction,
+        rate: Option<Decimal>,
+        mut destination: Option<&mut PaymentEngine>,
+    ) -> Result<Outcome, Rejection> {
+        let client_id = transaction.client;
+
+        // A new transaction ID is claimed in the index before anything else.
+        // The claim is given up if the transaction fails.
+        let claimed = transaction.kind.is_new_tx() && !self.tx_index.insert(transaction.tx);
+        if transaction.kind.is_new_tx()
+            && !claimed
+            && self.policy.tx_ids == TxIdScope::Global
+            && find_transaction(self.transactions.as_mut(), client_id, transaction.tx)?.is_none()
+        {
+            info!(
+                "Rejecting transaction. Transaction ID used by another client. Transaction ID: {}",
+                &transaction.tx
+            );
+            return Err(Rejection::TxIdInUse);
+        }
+
+        let destination_id = match (transaction.kind, transaction.destination) {
+            (TransactionType::Transfer, Some(destination_id)) => Some(destination_id),
+            _ => None,
+        };
+        let mut destination_account = destination_id.map(|destination_id| {
+            let existing = match destination.as_mut() {
+                Some(engine) => engine.client_accounts.remove(&destination_id),
+                None => self.client_accounts.remove(&destination_id),
+            };
+            let existed = existing.is_some();
+            (
+                existing.unwrap_or_else(|| ClientAccount::new(destination_id)),
+                existed,
+            )
+        });
+
+        let account = self
+            .client_accounts
+            .entry(client_id)
+            .or_insert_with(|| ClientAccount::new(client_id));
+
+        let fees = Fees {
+            schedule: self.fees.as_ref(),
+            precision: self.precision,
+        };
+        let processed = process_transaction(
+            account,
+            destination_account
+                .as_mut()
+                .map(|(destination_account, _)| destination_account),
+            self.transactions.as_mut(),
+            transaction,
+            rate,
+            &fees,
+            &self.policy,
+        );
+        self.precision.rescale(account);
+
+        if let Some((mut destination_account, existed)) = destination_account {
+            // A rejected transfer to a new client does not open an account.
+            if existed || processed.is_ok() {
+                self.precision.rescale(&mut destination_account);
+                let accounts = match destination {
+                    Some(engine) => &mut engine.client_accounts,
+                    None => &mut self.client_accounts,
+                };
+                accounts.insert(destination_account.client, destination_account);
+            }
+        }
+
+        let stored = match &processed {
+            Ok(_) => true,
+            Err(rejection) => self.policy.records_failure(transaction.kind, *rejection),
+        };
+        if claimed && !stored {
+            self.tx_index.remove(transaction.tx);
+        }
+        let processed = match processed {
+            Err(Rejection::NotDisputed | Rejection::UnknownReference)
+                if !transaction.kind.is_new_tx()
+                    && !transaction.kind.is_operator()
+                    && self.tx_index.contains(transaction.tx)
+                    && find_transaction(self.transactions.as_mut(), client_id, transaction.tx)?
+                        .is_none() =>
+            {
+                error!(
+                    "Rejecting transaction. Flagged: references another client's transaction. Client: {}. Referenced Transaction ID: {}",
+                    client_id, &transaction.tx
+                );
+                Err(Rejection::ForeignReference)
+            }
+            processed => processed,
+        };
+
+        let (outcome, charge) = processed?;
+        if let Some(charge) = charge {
+            self.credit_fee(&transaction, charge);
+        }
+        Ok(outcome)
+    }
+
+    // Credits a fee to the house account and records it in the fee ledger.
+    // The client has already paid it, so neither can reject the transaction
+    // any more.
+    fn credit_fee(&mut self, transaction: &ReadTransaction, charge: Charge) {
+        let house_id = match &self.fees {
+            Some(fees) => fees.house_account(),
+            None => return,
+        };
+        let house = self
+            .client_accounts
+            .entry(house_id)
+            .or_insert_with(|| ClientAccount::new(house_id));
+        match checked_balance(
+            house.balance(charge.currency),
+            charge.amount,
+            Decimal::ZERO,
+            transaction.tx,
+        ) {
+            Ok(balance) => {
+                house.balances.insert(charge.currency, balance);
+                self.precision.rescale(house);
+            }
+            Err(_) => error!(
+                "Unable to credit fee to house account. Transaction ID: {}",
+                &transaction.tx
+            ),
+        }
+        if let Some(fee_ledger) = self.fee_ledger.as_mut() {
+            if let Err(err) = fee_ledger.write(transaction, &charge, house_id) {
+                error!(
+                    "Unable to write fee ledger. Transaction ID: {}. Error: {}",
+                    &transaction.tx, err
+                );
+            }
+        }
+    }
+
+    /// Moves every account and stored transaction from `other` into this
+    /// engine. The two engines are expected to hold different clients.
+    pub(crate) fn absorb(&mut self, mut other: PaymentEngine) -> io::Result<()> {
+        self.client_accounts.append(&mut other.client_accounts);
+        self.tx_index.merge(&other.tx_index);
+        let transactions = self.transactions.as_mut();
+        other
+            .transactions
+            .for_each(&mut |client, tx, transaction| transactions.insert(client, tx, transaction))
+    }
+
+    /// Moves every account and stored transaction into the shard that
+    /// `ShardedEngine::shard_for` routes its client to. Every shard gets all
+    /// the transaction IDs used so far, whichever client they belong to.
+    pub(crate) fn split_into(mut self, shards: &mut [PaymentEngine]) -> io::Result<()> {
+        let count = shards.len();
+        for shard in shards.iter_mut() {
+            shard.tx_index.merge(&self.tx_index);
+        }
+        for (client, account) in std::mem::take(&mut self.client_accounts) {
+            shards[ShardedEngine::shard_for(client, count)]
+                .client_accounts
+                .insert(client, account);
+        }
+        self.transactions.for_each(&mut |client, tx, transaction| {
+            shards[ShardedEngine::shard_for(client, count)]
+                .transactions
+                .insert(client, tx, transaction)
+        })
+    }
+
+    pub fn account(&self, client: u16) -> Option<&ClientAccount> {
+        self.client_accounts.get(&client)
+    }
+
+    /// Every account, in client ID order.
+    pub fn accounts(&self) -> impl Iterator<Item = &ClientAccount> {
+        self.client_accounts.values()
+    }
+}
+
+fn process_chargeback(
+    account: &mut ClientAccount,
+    transactions: &mut dyn TransactionStore,
+    transaction: ReadTransaction,
+    fees: &Fees,
+) -> Result<Processed, Rejection> {
+    match account.disputes.get(&transaction.tx) {
+        Some(&disputed_transaction_id) => {
+            match find_transaction(transactions, account.client, disputed_transaction_id)? {
+                Some(disputed_transaction) => {
+                    let currency = disputed_transaction.currency;
+                    let fee = fees.fee(
+                        account.client,
+                        transaction.kind,
+                        disputed_transaction.amount,
+                    );
+                    let (available_change, held_change) =
+                        dispute_changes(&disputed_transaction, transaction.kind);
+                    let balance = checked_balance(
+                        account.balance(currency),
+                        available_change,
+                        held_change,
+                        transaction.tx,
+                    )?;
+                    let balance = charge_fee(balance, fee, false, transaction.tx)?;
+                    account.balances.insert(currency, balance);
+                    account.locked = true;
+                    account.disputes.remove(&transaction.tx);
+                    account.completed_disputes.insert(transaction.tx);
+                    Ok((Outcome::ChargedBack, Charge::new(fee, currency)))
+                }
+                None => {
+                    error!(
+                        "Unable to find disputed transaction. Referenced Transaction ID: {}",
+                        disputed_transaction_id
+                    );
+                    Err(Rejection::UnknownReference)
+                }
+            }
+        }
+        None => {
+            info!(
+                "Attempted to chargeback transaction not in dispute. Referenced Transaction ID: {}",
+                &transaction.tx
+            );
+            Err(Rejection::NotDisputed)
+        }
+    }
+}
+
+fn process_deposit(
+    account: &mut ClientAccount,
+    transactions: &mut dyn TransactionStore,
+    transaction: ReadTransaction,
+    rate: Option<Decimal>,
+    fees: &Fees,
+) -> Result<Processed, Rejection> {
+    let transaction_amount = required_amount(&transaction)?;
+    let fee = fees.fee(account.client, transaction.kind, transaction_amount);
+    let balance = checked_balance(
+        account.balance(transaction.currency),
+        transaction_amount,
+        Decimal::ZERO,
+        transaction.tx,
+    )?;
+    let balance = charge_fee(balance, fee, false, transaction.tx)?;
+
+    store_transaction(
+        transactions,
+        account.client,
+        transaction.tx,
+        InternalTransaction {
+            amount: transaction_amount,
+            kind: transaction.kind,
+            currency: transaction.currency,
+            rate,
+            timestamp: transaction.timestamp,
+            failed: false,
+        },
+    )?;
+    account.balances.insert(transaction.currency, balance);
+    Ok((Outcome::Deposited, Charge::new(fee, transaction.currency)))
+}
+
+fn process_dispute(
+    account: &mut ClientAccount,
+    transactions: &mut dyn TransactionStore,
+    transaction: ReadTransaction,
+    fees: &Fees,
+    policy: &Policy,
+) -> Result<Processed, Rejection> {
+    match find_transaction(transactions, account.client, transaction.tx)? {
+        Some(reference_transaction) => {
+            if reference_transaction.kind == TransactionType::Transfer {
+                warn!("Rejecting dispute. Transfers cannot be disputed. Referenced Transaction ID: {}", &transaction.tx);
+                return Err(Rejection::NotDisputable);
+            }
+            // A dispute without a timestamp of its own is as old as the
+            // client's latest transaction.
+            let disputed_at = transaction.timestamp.or(account.last_timestamp);
+            if let (Some(days), Some(disputed_at), Some(happened_at)) = (
+                policy.dispute_window,
+                disputed_at,
+                reference_transaction.timestamp,
+            ) {
+                if disputed_at.saturating_sub(happened_at) > days.saturating_mul(SECONDS_PER_DAY) {
+                    warn!("Rejecting dispute. Referenced transaction is older than the dispute window. Referenced Transaction ID: {}", &transaction.tx);
+                    return Err(Rejection::DisputeExpired);
+                }
+            }
+            if account.disputes.contains(&transaction.tx) {
+                warn!("Rejecting dispute. Referenced transaction already in dispute. Referenced Transaction ID: {}", &transaction.tx);
+                return Err(Rejection::AlreadyDisputed);
+            }
+            if policy.dispute_once && account.completed_disputes.contains(&transaction.tx) {
+                warn!("Rejecting dispute. Cannot dispute a transaction more than once. Referenced Transaction ID: {}", &transaction.tx);
+                return Err(Rejection::DisputeSettled);
+            }
+
+            // Funds are held in the currency of the disputed transaction,
+            // whatever currency the dispute row names.
+            let currency = reference_transaction.currency;
+            let fee = fees.fee(
+                account.client,
+                transaction.kind,
+                reference_transaction.amount,
+            );
+            let (available_change, held_change) =
+                dispute_changes(&reference_transaction, transaction.kind);
+            let balance = checked_balance(
+                account.balance(currency),
+                available_change,
+                held_change,
+                transaction.tx,
+            )?;
+            let balance = charge_fee(balance, fee, false, transaction.tx)?;
+            account.balances.insert(currency, balance);
+            account.disputes.insert(transaction.tx);
+            Ok((Outcome::Disputed, Charge::new(fee, currency)))
+        }
+        None => {
+            info!("Rejecting dispute. Referenced transaction not found. Referenced Transaction ID: {}", &transaction.tx);
+            Err(Rejection::UnknownReference)
+        }
+    }
+}
+
+// Freezing an account that is already locked is accepted, so the operator's
+// decision is on record even when a chargeback got there first.
+fn process_freeze(
+    account: &mut ClientAccount,
+    transaction: ReadTransaction,
+) -> Result<Processed, Rejection> {
+    warn!(
+        "Freezing account. Client: {}. Operator: {}. Transaction ID: {}",
+        account.client,
+        transaction.operator.unwrap_or_default(),
+        &transaction.tx
+    );
+    account.locked = true;
+    Ok((Outcome::Frozen, None))
+}
+
+fn process_resolve(
+    account: &mut ClientAccount,
+    transactions: &mut dyn TransactionStore,
+    transaction: ReadTransaction,
+    fees: &Fees,
+) -> Result<Processed, Rejection> {
+    let reference_transaction_disputed = account.disputes.contains(&transaction.tx);
+    if reference_transaction_disputed {
+        match find_transaction(transactions, account.client, transaction.tx)? {
+            Some(reference_transaction) => {
+                let currency = reference_transaction.currency;
+                let fee = fees.fee(
+                    account.client,
+                    transaction.kind,
+                    reference_transaction.amount,
+                );
+                let (available_change, held_change) =
+                    dispute_changes(&reference_transaction, transaction.kind);
+                let balance = checked_balance(
+                    account.balance(currency),
+                    available_change,
+                    held_change,
+                    transaction.tx,
+                )?;
+                let balance = charge_fee(balance, fee, false, transaction.tx)?;
+                account.balances.insert(currency, balance);
+                account.disputes.remove(&transaction.tx);
+                account.completed_disputes.insert(transaction.tx);
+                Ok((Outcome::Resolved, Charge::new(fee, currency)))
+            }
+            None => {
+                error!("Rejecting resolve. Referenced transaction not found. Referenced Transaction ID: {}", &transaction.tx);
+                Err(Rejection::UnknownReference)
+            }
+        }
+    } else {
+        info!(
+            "Rejecting resolve. Disputed transaction not found. Referenced Transaction ID: {}",
+            &transaction.tx
+        );
+        Err(Rejection::NotDisputed)
+    }
+}
+
+// The source keeps the transfer in its history so the ID cannot be reused.
+// Both balances are worked out before either account changes.
+fn process_transfer(
+    account: &mut ClientAccount,
+    destination: &mut ClientAccount,
+    transactions: &mut dyn TransactionStore,
+    transaction: ReadTransaction,
+    rate: Option<Decimal>,
+    fees: &Fees,
+) -> Result<Processed, Rejection> {
+    if destination.locked {
+        info!(
+            "Rejecting transfer. Destination account locked. Transaction ID: {}",
+            &transaction.tx
+        );
+        return Err(Rejection::DestinationLocked);
+    }
+    let transaction_amount = required_amount(&transaction)?;
+    let balance = account.balance(transaction.currency);
+
+    if transaction_amount > balance.available {
+        info!(
+            "Rejecting transfer. Cannot transfer more than available amount. Transaction ID: {}",
+            &transaction.tx
+        );
+        return Err(Rejection::InsufficientFunds);
+    }
+    let fee = fees.fee(account.client, transaction.kind, transaction_amount);
+    let balance = checked_balance(balance, -transaction_amount, Decimal::ZERO, transaction.tx)?;
+    let balance = charge_fee(balance, fee, true, transaction.tx)?;
+    let destination_balance = checked_balance(
+        destination.balance(transaction.currency),
+        transaction_amount,
+        Decimal::ZERO,
+        transaction.tx,
+    )?;
+
+    store_transaction(
+        transactions,
+        account.client,
+        transaction.tx,
+        InternalTransaction {
+            amount: transaction_amount,
+            kind: transaction.kind,
+            currency: transaction.currency,
+            rate,
+            timestamp: transaction.timestamp,
+            failed: false,
+        },
+    )?;
+    account.balances.insert(transaction.currency, balance);
+    destination
+        .balances
+        .insert(transaction.currency, destination_balance);
+    Ok((Outcome::Transferred, Charge::new(fee, transaction.currency)))
+}
+
+fn process_unlock(
+    account: &mut ClientAccount,
+    transaction: ReadTransaction,
+) -> Result<Processed, Rejection> {
+    if !account.locked {
+        info!(
+            "Rejecting unlock. Account not locked. Transaction ID: {}",
+            &transaction.tx
+        );
+        return Err(Rejection::NotLocked);
+    }
+    warn!(
+        "Unlocking account. Client: {}. Operator: {}. Transaction ID: {}",
+        account.client,
+        transaction.operator.unwrap_or_default(),
+        &transaction.tx
+    );
+    account.locked = false;
+    Ok((Outcome::Unlocked, None))
+}
+
+fn process_withdrawal(
+    account: &mut ClientAccount,
+    transactions: &mut dyn TransactionStore,
+    transaction: ReadTransaction,
+    rate: Option<Decimal>,
+    fees: &Fees,
+    policy: &Policy,
+) -> Result<Processed, Rejection> {
+    let transaction_amount = required_amount(&transaction)?;
+    let balance = account.balance(transaction.currency);
+    let fee = fees.fee(account.client, transaction.kind, transaction_amount);
+
+    let withdrawn = if transaction_amount <= balance.available {
+        let balance = checked_balance(balance, -transaction_amount, Decimal::ZERO, transaction.tx)?;
+        charge_fee(balance, fee, true, transaction.tx)
+    } else {
+        info!(
+            "Rejecting withdrawal. Cannot withdraw more than available amount. Transaction ID: {}",
+            &transaction.tx
+        );
+        Err(Rejection::InsufficientFunds)
+    };
+    let internal = InternalTransaction {
+        amount: transaction_amount,
+        kind: transaction.kind,
+        currency: transaction.currency,
+        rate,
+        timestamp: transaction.timestamp,
+        failed: false,
+    };
+
+    match withdrawn {
+        Ok(balance) => {
+            store_transaction(transactions, account.client, transaction.tx, internal)?;
+            account.balances.insert(transaction.currency, balance);
+            Ok((Outcome::Withdrawn, Charge::new(fee, transaction.currency)))
+        }
+        Err(Rejection::InsufficientFunds) => {
+            // Assumption - cannot dispute withdrawals that do not happen. This
+            // means failed withdrawals are not saved in the transaction log,
+            // unless the policy says they can be disputed.
+            if policy.dispute_failed_withdrawals {
+                store_transaction(
+                    transactions,
+                    account.client,
+                    transaction.tx,
+                    InternalTransaction {
+                        failed: true,
+                        ..internal
+                    },
+                )?;
+            }
+            Err(Rejection::InsufficientFunds)
+        }
+        Err(rejection) => Err(rejection),
+    }
+}
+
+// How a dispute, resolve or chargeback of `reference` changes the available
+// and held funds.
+//
+// Disputing a deposit holds the deposited funds. A resolve releases them and
+// a chargeback takes them away. Disputing a withdrawal holds a provisional
+// credit for the withdrawn amount instead, without touching the available
+// funds. A resolve drops the credit and a chargeback pays it back out.
+fn dispute_changes(reference: &InternalTransaction, kind: TransactionType) -> (Decimal, Decimal) {
+    let amount = reference.disputed_amount();
+    let withdrawal = reference.kind == TransactionType::Withdrawal;
+    match (kind, withdrawal) {
+        (TransactionType::Dispute, false) => (-amount, amount),
+        (TransactionType::Dispute, true) => (Decimal::ZERO, amount),
+        (TransactionType::Resolve, false) => (amount, -amount),
+        (TransactionType::Resolve, true) => (Decimal::ZERO, -amount),
+        // Chargebacks.
+        (_, false) => (Decimal::ZERO, -amount),
+        (_, true) => (amount, -amount),
+    }
+}
+
+// Works out a balance after a transaction without touching the account, so
+// an overflow anywhere, the total included, leaves the account as it was.
+fn checked_balance(
+    balance: Balance,
+    available_change: Decimal,
+    held_change: Decimal,
+    tx: u32,
+) -> Result<Balance, Rejection> {
+    let available = balance.available.checked_add(available_change);
+    let held = balance.held.checked_add(held_change);
+    let total = available
+        .zip(held)
+        .and_then(|(available, held)| available.checked_add(held));
+    match (available, held, total) {
+        // Taking an amount away can leave a negative zero, which would be
+        // written out as `-0.0000`.
+        (Some(available), Some(held), Some(total)) => Ok(Balance {
+            available: positive_zero(available),
+            held: positive_zero(held),
+            total: positive_zero(total),
+        }),
+        _ => {
+            error!(
+                "Rejecting transaction. Balance would overflow. Transaction ID: {}",
+                tx
+            );
+            Err(Rejection::BalanceOverflow)
+        }
+    }
+}
+
+fn positive_zero(mut amount: Decimal) -> Decimal {
+    if amount.is_zero() {
+        amount.set_sign_positive(true);
+    }
+    amount
+}
+
+// Takes a fee out of the available funds. Money leaving the engine, through
+// a withdrawal or transfer, has to cover its fee. Every other fee is charged
+// even if it leaves the available funds negative.
+fn charge_fee(
+    balance: Balance,
+    fee: Decimal,
+    covered: bool,
+    tx: u32,
+) -> Result<Balance, Rejection> {
+    if fee.is_zero() {
+        return Ok(balance);
+    }
+    if covered && fee > balance.available {
+        info!(
+            "Rejecting transaction. Cannot cover fee from available amount. Transaction ID: {}",
+            tx
+        );
+        return Err(Rejection::InsufficientFunds);
+    }
+    checked_balance(balance, -fee, Decimal::ZERO, tx)
+}
+
+// Deposits and withdrawals without an amount would otherwise panic on unwrap.
+fn required_amount(transaction: &ReadTransaction) -> Result<Decimal, Rejection> {
+    match transaction.amount {
+        Some(amount) => Ok(amount),
+        None => {
+            error!(
+                "Rejecting transaction. Missing amount. Transaction ID: {}",
+                &transaction.tx
+            );
+            Err(Rejection::MissingAmount)
+        }
+    }
+}
+
+fn find_transaction(
+    transactions: &mut dyn TransactionStore,
+    client: u16,
+    tx: u32,
+) -> Result<Option<InternalTransaction>, Rejection> {
+    transactions.get(client, tx).map_err(|err| {
+        error!(
+            "Rejecting transaction. Unable to read transaction store. Referenced Transaction ID: {}. Error: {}",
+            tx, err
+        );
+        Rejection::StoreFailed
+    })
+}
+
+fn store_transaction(
+    transactions: &mut dyn TransactionStore,
+    client: u16,
+    tx: u32,
+    transaction: InternalTransaction,
+) -> Result<(), Rejection> {
+    transactions.insert(client, tx, transaction).map_err(|err| {
+        error!(
+            "Rejecting transaction. Unable to write transaction store. Transaction ID: {}. Error: {}",
+            tx, err
+        );
+        Rejection::StoreFailed
+    })
+}
+
+fn process_transaction(
+    account: &mut ClientAccount,
+    destination: Option<&mut ClientAccount>,
+    transactions: &mut dyn TransactionStore,
+    transaction: ReadTransaction,
+    rate: Option<Decimal>,
+    fees: &Fees,
+    policy: &Policy,
+) -> Result<Processed, Rejection> {
+    // Assumption - once the account is locked we're 100% locked for this
+    // client. No further transactions are processed until an operator
+    // unlocks it, unless the locked account policy lets disputes carry on.
+    if account.locked && !policy.locked.allows(transaction.kind) {
+        info!(
+            "Rejecting transaction. Account locked. Referenced Transaction ID: {}",
+            &transaction.tx
+        );
+        return Err(Rejection::AccountLocked);
+    }
+
+    // Each client's transactions must come in time order. Rows without a
+    // timestamp are not checked.
+    if let (Some(timestamp), Some(last_timestamp)) = (transaction.timestamp, account.last_timestamp)
+    {
+        if timestamp < last_timestamp {
+            info!(
+                "Rejecting transaction. Timestamp is before the client's previous transaction. Transaction ID: {}",
+                &transaction.tx
+            );
+            return Err(Rejection::OutOfOrder);
+        }
+    }
+
+    if transaction.kind.is_new_tx()
+        && find_transaction(transactions, account.client, transaction.tx)?.is_some()
+    {
+        info!(
+            "Rejecting transaction. Duplicate transaction. Transaction ID: {}",
+            &transaction.tx
+        );
+        return Err(Rejection::DuplicateTx);
+    }
+
+    let processed = match transaction.kind {
+        TransactionType::Chargeback => process_chargeback(account, transactions, transaction, fees),
+        TransactionType::Deposit => process_deposit(account, transactions, transaction, rate, fees),
+        TransactionType::Dispute => {
+            process_dispute(account, transactions, transaction, fees, policy)
+        }
+        TransactionType::Freeze => process_freeze(account, transaction),
+        TransactionType::Resolve => process_resolve(account, transactions, transaction, fees),
+        TransactionType::Transfer => match destination {
+            Some(destination) => {
+                process_transfer(account, destination, transactions, transaction, rate, fees)
+            }
+            None => {
+                info!(
+                    "Rejecting transfer. Missing destination. Transaction ID: {}",
+                    &transaction.tx
+                );
+                Err(Rejection::MissingDestination)
+            }
+        },
+        TransactionType::Unlock => process_unlock(account, transaction),
+        TransactionType::Withdrawal => {
+            process_withdrawal(account, transactions, transaction, rate, fees, policy)
+        }
+    }?;
+    if transaction.timestamp.is_some() {
+        account.last_timestamp = transaction.timestamp;
+    }
+    Ok(processed)
+}
@@ -0,0 +1,144 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::model::*;
+
+const HEADER: &[u8] =
+    b"type,client,tx,amount,currency,destination,timestamp,operator,input,position\n";
+
+/// Append-only log of accepted transactions, written in the same CSV format
+/// the engine reads so it can be replayed with the normal input path.
+///
+/// A transaction is appended before it touches any account. If the engine
+/// then rejects it the append is rolled back, so the log only ever holds
+/// accepted transactions. A crash between the two leaves at most one extra
+/// row, which replay rejects again in exactly the same way.
+///
+/// Each row also records the input and byte position the transaction was
+/// read from, if any, so a restarted run knows how far it got.
+///
+/// Rows are flushed to the operating system on every append, which survives
+/// a process crash but not a power cut.
+#[derive(Debug)]
+pub struct EventLog {
+    file: File,
+    len: u64,
+    buffer: Vec<u8>,
+    input: String,
+    position: Option<u64>,
+}
+
+impl EventLog {
+    /// Opens the log at `path` for appending, creating it if needed. A row
+    /// cut short by a crash is dropped, so new rows start on a line of their
+    /// own.
+    pub fn open(path: &str) -> io::Result<EventLog> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut len = complete_len(&mut file)?;
+        if len != file.metadata()?.len() {
+            file.set_len(len)?;
+        }
+        if len == 0 {
+            file.write_all(HEADER)?;
+            len = HEADER.len() as u64;
+        }
+        Ok(EventLog {
+            file,
+            len,
+            buffer: Vec::new(),
+            input: String::new(),
+            position: None,
+        })
+    }
+
+    /// Records `input` as the source of the transactions appended from now on.
+    pub(crate) fn set_input(&mut self, input: &str) {
+        self.input.clear();
+        self.input.push_str(input);
+    }
+
+    /// Records the byte position in the input of the next transaction
+    /// appended. Transactions without one, such as those sent to a server,
+    /// are logged without a source.
+    pub(crate) fn set_position(&mut self, position: Option<u64>) {
+        self.position = position;
+    }
+
+    /// Appends a transaction and returns the log length before it, to be
+    /// passed to `rollback` if the transaction is rejected.
+    pub(crate) fn append(&mut self, transaction: &ReadTransaction) -> io::Result<u64> {
+        self.buffer.clear();
+        {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(&mut self.buffer);
+            writer.write_record([
+                transaction.kind.as_str().to_string(),
+                transaction.client.to_string(),
+                transaction.tx.to_string(),
+                transaction
+                    .amount
+                    .map_or(String::new(), |amount| amount.to_string()),
+                transaction
+                    .currency
+                    .map_or(String::new(), |currency| currency.to_string()),
+                transaction
+                    .destination
+                    .map_or(String::new(), |destination| destination.to_string()),
+                transaction
+                    .timestamp
+                    .map_or(String::new(), |timestamp| timestamp.to_string()),
+                transaction
+                    .operator
+                    .map_or(String::new(), |operator| operator.to_string()),
+                match self.position {
+                    Some(_) => self.input.clone(),
+                    None => String::new(),
+                },
+                self.position
+                    .map_or(String::new(), |position| position.to_string()),
+            ])?;
+            writer.flush()?;
+        }
+        self.file.write_all(&self.buffer)?;
+        self.file.flush()?;
+
+        let mark = self.len;
+        self.len += self.buffer.len() as u64;
+        Ok(mark)
+    }
+
+    pub(crate) fn rollback(&mut self, mark: u64) -> io::Result<()> {
+        self.file.set_len(mark)?;
+        self.len = mark;
+        Ok(())
+    }
+
+    /// Drops every logged transaction. Called once a snapshot holds them.
+    pub(crate) fn truncate(&mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        self.rollback(HEADER.len() as u64)
+    }
+}
+
+// The length of the log up to the end of its last complete row, found by
+// reading backwards from the end for a newline.
+fn complete_len(file: &mut File) -> io::Result<u64> {
+    let mut end = file.metadata()?.len();
+    let mut chunk = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let chunk = &mut chunk[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(newline) = chunk.iter().rposition(|&byte| byte == b'\n') {
+            return Ok(start + newline as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
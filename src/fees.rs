@@ -0,0 +1,123 @@
+use rust_decimal::prelude::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+
+use crate::model::*;
+
+/// Fees charged per transaction type, credited to a house account.
+///
+/// The schedule is a CSV file with a `type,flat,percentage,min,max` header
+/// and at most one row per transaction type. A fee is the flat amount plus
+/// the percentage of the transaction's amount, then raised to `min` and
+/// capped at `max`. Every column but `type` may be left empty.
+#[derive(Clone, Debug, Default)]
+pub struct FeeSchedule {
+    fees: HashMap<TransactionType, Fee>,
+    house_account: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Fee {
+    flat: Decimal,
+    percentage: Decimal,
+    min: Option<Decimal>,
+    max: Option<Decimal>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> FeeSchedule {
+        FeeSchedule::default()
+    }
+
+    pub fn from_path(path: &str) -> Result<FeeSchedule, Box<dyn Error>> {
+        FeeSchedule::from_reader(File::open(path)?)
+    }
+
+    pub fn from_reader(reader: impl io::Read) -> Result<FeeSchedule, Box<dyn Error>> {
+        let mut schedule = FeeSchedule::new();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(reader);
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |position| position.line());
+            let kind = record
+                .get(0)
+                .and_then(|kind| TransactionType::from_str(kind).ok())
+                .ok_or_else(|| format!("fee schedule line {} has an invalid type", line))?;
+            let column = |index: usize| -> Result<Option<Decimal>, Box<dyn Error>> {
+                match record.get(index) {
+                    None | Some("") => Ok(None),
+                    Some(value) => match Decimal::from_str(value) {
+                        Ok(value) if value >= Decimal::ZERO => Ok(Some(value)),
+                        _ => {
+                            Err(format!("fee schedule line {} has an invalid amount", line).into())
+                        }
+                    },
+                }
+            };
+            schedule.set_fee(
+                kind,
+                column(1)?.unwrap_or(Decimal::ZERO),
+                column(2)?.unwrap_or(Decimal::ZERO),
+                column(3)?,
+                column(4)?,
+            );
+        }
+        Ok(schedule)
+    }
+
+    /// Charges `kind` transactions `flat` plus `percentage` percent of their
+    /// amount, kept between `min` and `max`.
+    pub fn set_fee(
+        &mut self,
+        kind: TransactionType,
+        flat: Decimal,
+        percentage: Decimal,
+        min: Option<Decimal>,
+        max: Option<Decimal>,
+    ) {
+        self.fees.insert(
+            kind,
+            Fee {
+                flat,
+                percentage,
+                min,
+                max,
+            },
+        );
+    }
+
+    /// The client fees are credited to. Defaults to client 0. The house
+    /// account is never charged fees itself.
+    pub fn house_account(&self) -> u16 {
+        self.house_account
+    }
+
+    pub fn set_house_account(&mut self, client: u16) {
+        self.house_account = client;
+    }
+
+    /// The fee for a `kind` transaction of `amount` charged to `client`, or
+    /// zero if there is none.
+    pub fn fee(&self, client: u16, kind: TransactionType, amount: Decimal) -> Decimal {
+        let fee = match self.fees.get(&kind) {
+            Some(fee) if client != self.house_account => fee,
+            _ => return Decimal::ZERO,
+        };
+        let mut charged = amount
+            .checked_mul(fee.percentage / Decimal::ONE_HUNDRED)
+            .and_then(|charged| charged.checked_add(fee.flat))
+            .unwrap_or(Decimal::MAX);
+        if let Some(min) = fee.min {
+            charged = charged.max(min);
+        }
+        if let Some(max) = fee.max {
+            charged = charged.min(max);
+        }
+        charged
+    }
+}
@@ -0,0 +1,128 @@
+use serde::Serialize;
+use std::error::Error;
+use std::io::Cursor;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::engine::PaymentEngine;
+use crate::model::*;
+use crate::output::AccountRow;
+
+// Requests are handled by a few threads sharing one engine. The engine lock
+// is only held while a transaction is applied or an account is read.
+const HTTP_THREADS: usize = 4;
+
+#[derive(Serialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+enum TransactionResponse {
+    Accepted { outcome: &'static str },
+    Rejected { reason: &'static str },
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Serves the engine over HTTP until the listener fails.
+///
+/// - `POST /transactions` takes a JSON transaction such as
+///   `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}` and answers
+///   `{"result": "accepted", "outcome": "deposited"}` or
+///   `{"result": "rejected", "reason": "insufficient_funds"}`.
+/// - `GET /accounts/{client}` answers with a list of the account's balances,
+///   one per currency, each with its open disputes.
+pub fn serve_http(listener: TcpListener, engine: PaymentEngine) -> Result<(), Box<dyn Error>> {
+    let server = Arc::new(Server::from_listener(listener, None).map_err(|err| err.to_string())?);
+    let engine = Arc::new(Mutex::new(engine));
+
+    let workers: Vec<_> = (0..HTTP_THREADS)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    if let Err(err) = handle_request(request, &engine) {
+                        warn!("Unable to answer HTTP request. Error: {}", err);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().map_err(|_| "HTTP worker panicked")?;
+    }
+    Ok(())
+}
+
+fn handle_request(mut request: Request, engine: &Mutex<PaymentEngine>) -> std::io::Result<()> {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let response = match (request.method(), segments.as_slice()) {
+        (Method::Post, ["transactions"]) => {
+            match serde_json::from_reader::<_, ReadTransaction>(request.as_reader()) {
+                Ok(transaction) => {
+                    let result = match engine.lock() {
+                        Ok(mut engine) => engine.apply(transaction),
+                        Err(_) => return request.respond(error(500, "payment engine unavailable")),
+                    };
+                    match result {
+                        Ok(outcome) => json(
+                            200,
+                            &TransactionResponse::Accepted {
+                                outcome: outcome.as_str(),
+                            },
+                        ),
+                        Err(rejection) => json(
+                            422,
+                            &TransactionResponse::Rejected {
+                                reason: rejection.code(),
+                            },
+                        ),
+                    }
+                }
+                Err(err) => error(400, &format!("invalid transaction: {}", err)),
+            }
+        }
+        (Method::Get, ["accounts", client]) => match client.parse::<u16>() {
+            Ok(client) => {
+                let engine = match engine.lock() {
+                    Ok(engine) => engine,
+                    Err(_) => return request.respond(error(500, "payment engine unavailable")),
+                };
+                match engine.account(client) {
+                    Some(account) => json(
+                        200,
+                        &AccountRow::rows(account, engine.precision.scale, true),
+                    ),
+                    None => error(404, "account not found"),
+                }
+            }
+            Err(_) => error(400, "invalid client"),
+        },
+        (_, ["transactions"]) | (_, ["accounts", _]) => error(405, "method not allowed"),
+        _ => error(404, "not found"),
+    };
+    request.respond(response)
+}
+
+fn json(status: u16, body: &impl Serialize) -> Response<Cursor<Vec<u8>>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn error(status: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+    json(
+        status,
+        &ErrorResponse {
+            error: message.to_string(),
+        },
+    )
+}
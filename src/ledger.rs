@@ -0,0 +1,45 @@
+use std::error::Error;
+use std::fs::File;
+use std::io;
+
+use crate::engine::Charge;
+use crate::model::*;
+
+/// Writes every fee the engine charges to a CSV file, one row per fee, next
+/// to the transaction that caused it.
+#[derive(Debug)]
+pub struct FeeLedger {
+    writer: csv::Writer<File>,
+}
+
+impl FeeLedger {
+    pub fn from_path(path: &str) -> Result<FeeLedger, Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(File::create(path)?);
+        writer.write_record(["client", "tx", "type", "fee", "currency", "house_account"])?;
+        Ok(FeeLedger { writer })
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        transaction: &ReadTransaction,
+        fee: &Charge,
+        house_account: u16,
+    ) -> io::Result<()> {
+        self.writer.write_record([
+            transaction.client.to_string(),
+            transaction.tx.to_string(),
+            transaction.kind.as_str().to_string(),
+            fee.amount.to_string(),
+            fee.currency
+                .map_or(String::new(), |currency| currency.to_string()),
+            house_account.to_string(),
+        ])?;
+        // Flushed on every write, like the event log, so a server that never
+        // finishes a run does not lose fees sitting in the buffer.
+        self.writer.flush()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
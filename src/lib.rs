@@ -0,0 +1,644 @@
+extern crate csv;
+extern crate serde;
+#[macro_use]
+extern crate log;
+
+use csv::StringRecord;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use rust_decimal::prelude::*;
+
+mod engine;
+mod event_log;
+mod fees;
+mod http;
+mod ledger;
+pub mod model;
+mod output;
+mod policy;
+mod precision;
+mod rates;
+mod rejects;
+mod server;
+mod sharded;
+mod snapshot;
+mod store;
+#[allow(clippy::module_inception)]
+mod tests;
+mod tx_index;
+mod validation;
+
+pub use engine::{Outcome, PaymentEngine, Rejection};
+pub use event_log::EventLog;
+pub use fees::FeeSchedule;
+pub use http::serve_http;
+pub use ledger::FeeLedger;
+use model::*;
+pub use output::OutputFormat;
+pub use policy::{LockedPolicy, Policy, TxIdScope};
+pub use precision::{Precision, Rounding, DEFAULT_SCALE};
+pub use rates::RateTable;
+pub use rejects::RejectsWriter;
+pub use server::serve;
+pub use sharded::ShardedEngine;
+pub use snapshot::SNAPSHOT_VERSION;
+pub use store::{DiskStore, MemoryStore, TransactionStore, DEFAULT_CACHE_SIZE};
+
+/// Why an input row could not be turned into a `ReadTransaction`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    InvalidAmount,
+    InvalidClient,
+    InvalidCurrency,
+    InvalidDestination,
+    InvalidOperator,
+    InvalidTimestamp,
+    InvalidTx,
+    InvalidType,
+    MissingClient,
+    MissingDestination,
+    MissingOperator,
+    MissingTx,
+    MissingType,
+    /// A dispute, resolve, chargeback or operator action had an amount that
+    /// could not be read. Any amount is unexpected on them.
+    UnexpectedAmount,
+    /// The CSV reader could not read the row at all.
+    Unreadable,
+}
+
+impl ParseError {
+    /// Machine-readable reason code used in the rejects report.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::InvalidAmount => "invalid_amount",
+            ParseError::InvalidClient => "invalid_client",
+            ParseError::InvalidCurrency => "invalid_currency",
+            ParseError::InvalidDestination => "invalid_destination",
+            ParseError::InvalidOperator => "invalid_operator",
+            ParseError::InvalidTimestamp => "invalid_timestamp",
+            ParseError::InvalidTx => "invalid_tx",
+            ParseError::InvalidType => "invalid_type",
+            ParseError::MissingClient => "missing_client",
+            ParseError::MissingDestination => "missing_destination",
+            ParseError::MissingOperator => "missing_operator",
+            ParseError::MissingTx => "missing_tx",
+            ParseError::MissingType => "missing_type",
+            ParseError::UnexpectedAmount => "unexpected_amount",
+            ParseError::Unreadable => "unreadable_row",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+impl Error for ParseError {}
+
+/// Everything the CLI can ask of a run.
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    /// Input files, processed in order into the same engine. `-` reads
+    /// standard input.
+    pub inputs: Vec<String>,
+    /// Where to write rejected input rows, if anywhere.
+    pub rejects: Option<String>,
+    /// Engine state to resume from and save back to after the run.
+    pub snapshot: Option<String>,
+    /// Log of accepted transactions. Replayed on startup, then appended to.
+    pub event_log: Option<String>,
+    /// Keep the transaction history in this file instead of memory.
+    pub disk_store: Option<String>,
+    /// How many transactions the disk store keeps in memory. Defaults to
+    /// `DEFAULT_CACHE_SIZE`.
+    pub cache_size: Option<usize>,
+    /// Number of worker threads. Transactions are sharded across them by
+    /// client. Runs sequentially when unset.
+    pub threads: Option<usize>,
+    pub output_format: OutputFormat,
+    /// Decimal places amounts are kept to, and what to do with amounts that
+    /// have more.
+    pub precision: Precision,
+    /// The rules transactions are processed by.
+    pub policy: Policy,
+    /// Rate table to convert other currencies into the base currency with.
+    /// Balances are kept per currency when unset.
+    pub rates: Option<String>,
+    /// The currency accounts are settled in when converting.
+    pub base_currency: Option<Currency>,
+    /// Spread taken on conversions, as a fraction.
+    pub spread: Option<Decimal>,
+    /// Fee schedule to charge transactions by.
+    pub fees: Option<String>,
+    /// The client fees are credited to. Defaults to client 0.
+    pub house_account: Option<u16>,
+    /// Where to write every fee charged, if anywhere.
+    pub fee_ledger: Option<String>,
+    /// Days after a transaction that it can still be disputed.
+    pub dispute_window: Option<u64>,
+    /// Add extended fields, such as open disputes, to JSON output.
+    pub extended: bool,
+}
+
+// Handling the record manually allows for robust CSV handling
+// Serde automatic deserialization didn't like Option<Decimal> (or I couldn't
+// get it work anyway)
+// Serde tuple deserialization was a little better, but if the line ending in
+// the CSV didn't have a comma it would throw.
+//
+// Fields are trimmed here rather than by the reader so the rejects report can
+// hold the row exactly as it was read.
+fn deserialize_transaction(record: &StringRecord) -> Result<ReadTransaction, ParseError> {
+    let line = record.position().map_or(0, |position| position.line());
+    let kind = if !record.is_empty() {
+        match TransactionType::from_str(record[0].trim()) {
+            Ok(val) => val,
+            Err(err) => {
+                error!(
+                    "Rejecting transaction. Unable to read transaction type from CSV. Line: {}. Error: {:?}",
+                    line, err
+                );
+                return Err(ParseError::InvalidType);
+            }
+        }
+    } else {
+        error!(
+            "Rejecting transaction. Unable to read transaction type from CSV. Line: {}. Not enough fields.",
+            line
+        );
+        return Err(ParseError::MissingType);
+    };
+    let client = if record.len() > 1 {
+        match record[1].trim().parse::<u16>() {
+            Ok(val) => val,
+            Err(err) => {
+                error!(
+                    "Rejecting transaction. Unable to read client from CSV. Line: {}. Error: {:?}",
+                    line, err
+                );
+                return Err(ParseError::InvalidClient);
+            }
+        }
+    } else {
+        error!(
+            "Rejecting transaction. Unable to read client from CSV. Line: {}. Not enough fields.",
+            line
+        );
+        return Err(ParseError::MissingClient);
+    };
+    let tx = if record.len() > 2 {
+        match record[2].trim().parse::<u32>() {
+            Ok(val) => val,
+            Err(err) => {
+                error!(
+                    "Rejecting transaction. Unable to read tx from CSV. Line: {}. Error: {:?}",
+                    line, err
+                );
+                return Err(ParseError::InvalidTx);
+            }
+        }
+    } else {
+        error!(
+            "Rejecting transaction. Unable to read tx from CSV. Line: {}. Not enough fields.",
+            line
+        );
+        return Err(ParseError::MissingTx);
+    };
+    // Disputes, resolves and chargebacks take the amount of the transaction
+    // they reference, so any amount on them is rejected, even one that does
+    // not parse.
+    let amount = match record.get(3).map(str::trim) {
+        None => None,
+        Some("") if !kind.is_new_tx() => None,
+        Some(amount) => match Decimal::from_str(amount) {
+            Ok(val) => Some(val),
+            Err(err) if kind.is_new_tx() => {
+                error!(
+                    "Rejecting transaction. Unable to read amount from CSV. Line: {}. Error: {:?}",
+                    line, err
+                );
+                return Err(ParseError::InvalidAmount);
+            }
+            Err(_) => {
+                warn!(
+                    "Rejecting transaction. Amount given for a transaction that references another. Line: {}",
+                    line
+                );
+                return Err(ParseError::UnexpectedAmount);
+            }
+        },
+    };
+    // The currency column is optional. Leaving it out, or empty, means the
+    // default currency.
+    let currency = match record.get(4).map(str::trim) {
+        None | Some("") => None,
+        Some(code) => match Currency::from_str(code) {
+            Ok(val) => Some(val),
+            Err(_) => {
+                error!(
+                    "Rejecting transaction. Unable to read currency from CSV. Line: {}. Currency: {}",
+                    line, code
+                );
+                return Err(ParseError::InvalidCurrency);
+            }
+        },
+    };
+
+    // Only transfers have a destination client.
+    let destination = match record.get(5).map(str::trim) {
+        None | Some("") if kind == TransactionType::Transfer => {
+            error!(
+                "Rejecting transaction. Unable to read destination from CSV. Line: {}. Not enough fields.",
+                line
+            );
+            return Err(ParseError::MissingDestination);
+        }
+        None | Some("") => None,
+        Some(destination) => match destination.parse::<u16>() {
+            Ok(val) => Some(val),
+            Err(err) => {
+                error!(
+                    "Rejecting transaction. Unable to read destination from CSV. Line: {}. Error: {:?}",
+                    line, err
+                );
+                return Err(ParseError::InvalidDestination);
+            }
+        },
+    };
+
+    // The timestamp column is optional too, in seconds since the Unix epoch.
+    let timestamp = match record.get(6).map(str::trim) {
+        None | Some("") => None,
+        Some(timestamp) => match timestamp.parse::<u64>() {
+            Ok(val) => Some(val),
+            Err(err) => {
+                error!(
+                    "Rejecting transaction. Unable to read timestamp from CSV. Line: {}. Error: {:?}",
+                    line, err
+                );
+                return Err(ParseError::InvalidTimestamp);
+            }
+        },
+    };
+
+    // Only freezes and unlocks name the operator who sent them.
+    let operator = match record.get(7).map(str::trim) {
+        None | Some("") if kind.is_operator() => {
+            error!(
+                "Rejecting transaction. Unable to read operator from CSV. Line: {}. Not enough fields.",
+                line
+            );
+            return Err(ParseError::MissingOperator);
+        }
+        None | Some("") => None,
+        Some(operator) => match operator.parse::<u32>() {
+            Ok(val) => Some(val),
+            Err(err) => {
+                error!(
+                    "Rejecting transaction. Unable to read operator from CSV. Line: {}. Error: {:?}",
+                    line, err
+                );
+                return Err(ParseError::InvalidOperator);
+            }
+        },
+    };
+
+    Ok(ReadTransaction {
+        kind,
+        client,
+        tx,
+        amount,
+        currency,
+        destination,
+        timestamp,
+        operator,
+    })
+}
+
+pub fn do_run(input_filename: &str, stdout: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+    run(
+        &RunOptions {
+            inputs: vec![input_filename.to_string()],
+            ..RunOptions::default()
+        },
+        stdout,
+    )
+}
+
+pub fn run(options: &RunOptions, stdout: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+    let threads = options.threads.unwrap_or(1);
+    if threads > 1 && options.event_log.is_some() {
+        return Err("--event-log cannot be combined with --threads".into());
+    }
+    if threads > 1 && options.fees.is_some() {
+        return Err("--fees cannot be combined with --threads".into());
+    }
+    if options.extended && !options.output_format.is_json() {
+        return Err("--extended is only supported by the json and ndjson formats".into());
+    }
+
+    let (mut engine, progress) = recover_engine(options)?;
+
+    let mut rejects = match &options.rejects {
+        Some(path) => Some(RejectsWriter::from_path(path)?),
+        None => None,
+    };
+
+    if threads > 1 {
+        engine = run_sharded(engine, threads, options, &mut rejects)?;
+    } else {
+        for input in &options.inputs {
+            // Rows the event log already got past were applied before a
+            // crash, or rejected then, and are not read again.
+            let resume = progress.get(input).copied();
+            if let Some(event_log) = engine.event_log_mut() {
+                event_log.set_input(input);
+            }
+            let mut reader = open_input(input)?;
+            for (record, parsed) in read_transactions(&mut reader) {
+                let position = record.position().map(|position| position.byte());
+                if let (Some(resume), Some(position)) = (resume, position) {
+                    if position <= resume {
+                        continue;
+                    }
+                }
+                if let Some(event_log) = engine.event_log_mut() {
+                    event_log.set_position(position);
+                }
+                let reason = match parsed {
+                    Ok(transaction) => match engine.apply(transaction) {
+                        Ok(_) => continue,
+                        Err(rejection) => rejection.code(),
+                    },
+                    Err(err) => err.code(),
+                };
+                if let Some(rejects) = rejects.as_mut() {
+                    rejects.write(input, record.position(), reason, &record)?;
+                }
+            }
+        }
+    }
+
+    if let Some(rejects) = rejects.as_mut() {
+        rejects.flush()?;
+    }
+    if let Some(fee_ledger) = engine.fee_ledger_mut() {
+        fee_ledger.flush()?;
+    }
+
+    if let Some(path) = &options.snapshot {
+        engine.checkpoint(path)?;
+    }
+
+    output::write_accounts(
+        engine.accounts(),
+        engine.precision.scale,
+        options.output_format,
+        options.extended,
+        stdout,
+    )
+}
+
+/// Builds the engine described by `options`: its transaction store, the
+/// snapshot it resumes from, and the event log it recovers from and appends
+/// to.
+pub fn open_engine(options: &RunOptions) -> Result<PaymentEngine, Box<dyn Error>> {
+    Ok(recover_engine(options)?.0)
+}
+
+// How far into each input the event log got, as the byte position of the
+// last row logged from it.
+type Progress = HashMap<String, u64>;
+
+// Builds the engine like `open_engine`, and also returns how far into each
+// input the replayed event log got.
+fn recover_engine(options: &RunOptions) -> Result<(PaymentEngine, Progress), Box<dyn Error>> {
+    let mut engine = PaymentEngine::with_store(open_store(options, None)?);
+    engine.set_precision(options.precision);
+    engine.set_policy(options.policy);
+    if let Some(days) = options.dispute_window {
+        engine.set_dispute_window(days);
+    }
+
+    match &options.rates {
+        Some(path) => {
+            let mut rates = RateTable::from_path(path)?;
+            if let Some(base) = options.base_currency {
+                rates.set_base(base);
+            }
+            if let Some(spread) = options.spread {
+                rates.set_spread(spread);
+            }
+            engine.set_rates(rates);
+        }
+        None if options.base_currency.is_some() || options.spread.is_some() => {
+            return Err("--base-currency and --spread need --rates".into());
+        }
+        None => {}
+    }
+
+    // Fees are set up before the event log is replayed, so the replay charges
+    // them again.
+    match &options.fees {
+        Some(path) => {
+            let mut fees = FeeSchedule::from_path(path)?;
+            if let Some(house_account) = options.house_account {
+                fees.set_house_account(house_account);
+            }
+            engine.set_fees(fees);
+        }
+        None if options.house_account.is_some() || options.fee_ledger.is_some() => {
+            return Err("--house-account and --fee-ledger need --fees".into());
+        }
+        None => {}
+    }
+
+    if let Some(path) = &options.snapshot {
+        engine.load_snapshot(path)?;
+    }
+
+    let mut progress = Progress::new();
+    if let Some(path) = &options.event_log {
+        if Path::new(path).exists() {
+            progress = replay_event_log(&mut engine, path)?;
+        }
+        engine.set_event_log(EventLog::open(path)?);
+    }
+
+    // Only fees charged from here on are written, not those charged again by
+    // the replay.
+    if let Some(path) = &options.fee_ledger {
+        engine.set_fee_ledger(FeeLedger::from_path(path)?);
+    }
+
+    Ok((engine, progress))
+}
+
+// Rejected rows come back from the workers out of order, so they are
+// collected and written sorted by input and position. The report then
+// matches a sequential run.
+fn run_sharded(
+    engine: PaymentEngine,
+    threads: usize,
+    options: &RunOptions,
+    rejects: &mut Option<RejectsWriter<File>>,
+) -> Result<PaymentEngine, Box<dyn Error>> {
+    let mut shards = Vec::new();
+    for shard in 0..threads {
+        let mut shard_engine = PaymentEngine::with_store(open_store(options, Some(shard))?);
+        shard_engine.set_precision(engine.precision);
+        if let Some(rates) = &engine.rates {
+            shard_engine.set_rates(rates.clone());
+        }
+        shard_engine.set_policy(engine.policy);
+        shards.push(shard_engine);
+    }
+    engine.split_into(&mut shards)?;
+
+    let mut sharded = ShardedEngine::new(shards);
+    let mut rejected = Vec::new();
+    for (index, input) in options.inputs.iter().enumerate() {
+        let mut reader = open_input(input)?;
+        for (record, parsed) in read_transactions(&mut reader) {
+            match parsed {
+                Ok(transaction) => {
+                    sharded.submit(transaction, rejects.as_ref().map(|_| (index, record)))
+                }
+                Err(err) => {
+                    if rejects.is_some() {
+                        rejected.push((err.code(), (index, record)));
+                    }
+                }
+            }
+        }
+    }
+
+    let (engine, shard_rejected) = sharded.finish()?;
+    if let Some(rejects) = rejects.as_mut() {
+        rejected.extend(
+            shard_rejected
+                .into_iter()
+                .map(|(rejection, tag)| (rejection.code(), tag)),
+        );
+        rejected.sort_by_key(|(_, (index, record))| {
+            (*index, record.position().map(|position| position.byte()))
+        });
+        for (reason, (index, record)) in rejected {
+            rejects.write(&options.inputs[index], record.position(), reason, &record)?;
+        }
+    }
+    Ok(engine)
+}
+
+fn open_input(input: &str) -> Result<csv::Reader<Box<dyn io::Read>>, Box<dyn Error>> {
+    let source: Box<dyn io::Read> = if input == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(input)?)
+    };
+    Ok(csv::ReaderBuilder::new().flexible(true).from_reader(source))
+}
+
+// Each shard gets its own file when the history is kept on disk.
+fn open_store(
+    options: &RunOptions,
+    shard: Option<usize>,
+) -> Result<Box<dyn TransactionStore>, Box<dyn Error>> {
+    match &options.disk_store {
+        Some(path) => {
+            let path = match shard {
+                Some(shard) => format!("{}.{}", path, shard),
+                None => path.clone(),
+            };
+            let cache_size = options.cache_size.unwrap_or(DEFAULT_CACHE_SIZE);
+            Ok(Box::new(DiskStore::create(&path, cache_size)?))
+        }
+        None => Ok(Box::new(MemoryStore::new())),
+    }
+}
+
+// Yields every row along with the transaction parsed from it. A row the CSV
+// reader cannot read at all comes back as an empty record carrying the
+// position of the error.
+fn read_transactions<R: io::Read>(
+    reader: &mut csv::Reader<R>,
+) -> impl Iterator<Item = (StringRecord, Result<ReadTransaction, ParseError>)> + '_ {
+    reader.records().map(|result| match result {
+        Ok(record) => {
+            let parsed = deserialize_transaction(&record);
+            (record, parsed)
+        }
+        Err(err) => {
+            error!(
+                "Rejecting transaction. Unable to read transaction from CSV. Error: {}",
+                err
+            );
+            let mut record = StringRecord::new();
+            record.set_position(err.position().cloned());
+            (record, Err(ParseError::Unreadable))
+        }
+    })
+}
+
+/// Rebuilds the engine state from an event log and writes the balances.
+/// `options` should set up the engine the way the run that wrote the log did,
+/// so fees, precision and policy come out the same.
+pub fn replay(
+    event_log: &str,
+    options: &RunOptions,
+    stdout: &mut dyn io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut engine = open_engine(options)?;
+    replay_event_log(&mut engine, event_log)?;
+    write_accounts(&engine, stdout)
+}
+
+// Replay goes through `deserialize_transaction` and `apply` exactly like the
+// original input did. A row cut short by a crash fails to parse and is
+// skipped. Returns how far into each input the log got.
+fn replay_event_log(engine: &mut PaymentEngine, path: &str) -> Result<Progress, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_path(path)?;
+    let mut replayed = 0;
+    let mut progress = Progress::new();
+    for (record, parsed) in read_transactions(&mut reader) {
+        match parsed {
+            Ok(transaction) => {
+                if let (Some(input), Some(Ok(position))) = (
+                    record.get(8).filter(|input| !input.is_empty()),
+                    record.get(9).map(str::parse::<u64>),
+                ) {
+                    progress.insert(input.to_string(), position);
+                }
+                if engine.apply(transaction).is_ok() {
+                    replayed += 1;
+                }
+            }
+            Err(err) => {
+                warn!("Skipping unreadable event log row. Error: {}", err);
+            }
+        }
+    }
+    info!("Replayed {} transactions from {}", replayed, path);
+    Ok(progress)
+}
+
+fn write_accounts(
+    engine: &PaymentEngine,
+    stdout: &mut dyn io::Write,
+) -> Result<(), Box<dyn Error>> {
+    output::write_accounts(
+        engine.accounts(),
+        engine.precision.scale,
+        OutputFormat::Csv,
+        false,
+        stdout,
+    )
+}
@@ -1,312 +1,206 @@
-extern crate csv;
-extern crate serde;
 #[macro_use]
 extern crate log;
 
-use csv::StringRecord;
-use std::collections::HashMap;
-use std::collections::HashSet;
 use std::env;
 use std::error::Error;
-use std::ffi::OsString;
 use std::io;
+use std::net::TcpListener;
 use std::process;
 
-use rust_decimal::prelude::*;
-
-mod model;
-mod tests;
-use model::*;
+use payment_engine::{LockedPolicy, Policy, RunOptions};
+use rust_decimal::Decimal;
+
+// The most decimal places a `Decimal` can hold.
+const MAX_SCALE: u32 = 28;
+
+// Replay only rebuilds the state the log describes and prints it. It saves
+// nothing and writes no log of its own.
+const REPLAY_UNSUPPORTED: [&str; 7] = [
+    "--rejects",
+    "--threads",
+    "--output-format",
+    "--extended",
+    "--snapshot",
+    "--event-log",
+    "--fee-ledger",
+];
+
+// A server never finishes a run, so there is no point to save a snapshot at.
+const SERVER_UNSUPPORTED: [&str; 5] = [
+    "--rejects",
+    "--threads",
+    "--output-format",
+    "--extended",
+    "--snapshot",
+];
+
+enum Command {
+    Run(RunOptions),
+    Replay(String, RunOptions),
+    Serve(String, RunOptions),
+    ServeHttp(String, RunOptions),
+}
 
 fn main() {
     env_logger::init();
 
-    let input_filename = get_first_arg().unwrap().into_string().unwrap();
+    let command = match parse_command(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(2);
+        }
+    };
 
-    // While this program is not multithreaded it would be trivial to
-    // spin up a thread and execute `do_run` on its own thread.
-    if let Err(err) = do_run(&input_filename, &mut io::stdout()) {
+    let result = match command {
+        Command::Run(options) => payment_engine::run(&options, &mut io::stdout()),
+        Command::Replay(event_log, options) => {
+            payment_engine::replay(&event_log, &options, &mut io::stdout())
+        }
+        Command::Serve(address, options) => serve(&address, &options),
+        Command::ServeHttp(address, options) => serve_http(&address, &options),
+    };
+    if let Err(err) = result {
         println!("{}", err);
         process::exit(1);
     }
 }
 
-fn get_first_arg() -> Result<OsString, Box<dyn Error>> {
-    match env::args_os().nth(1) {
-        None => Err(From::from("expected 1 argument, but got none")),
-        Some(file_path) => Ok(file_path),
-    }
+fn serve(address: &str, options: &RunOptions) -> Result<(), Box<dyn Error>> {
+    let engine = payment_engine::open_engine(options)?;
+    let listener = TcpListener::bind(address)?;
+    info!("Listening on {}", listener.local_addr()?);
+    payment_engine::serve(listener, engine)
 }
 
-fn process_chargeback(account: &mut ClientAccount, transaction: ReadTransaction) {
-    match account.disputes.get(&transaction.tx) {
-        Some(disputed_transaction_id) => {
-            match account.transactions.get_mut(disputed_transaction_id) {
-                Some(disputed_transaction) => {
-                    account.held -= disputed_transaction.amount;
-                    account.locked = true;
-                    account.disputes.remove(&transaction.tx);
-                    account.completed_disputes.insert(transaction.tx);
-                }
-                None => {
-                    error!(
-                        "Unable to find disputed transaction. Referenced Transaction ID: {}",
-                        disputed_transaction_id
-                    );
-                }
-            }
-        }
-        None => {
-            info!(
-                "Attempted to chargeback transaction not in dispute. Referenced Transaction ID: {}",
-                &transaction.tx
-            );
-        }
-    }
+fn serve_http(address: &str, options: &RunOptions) -> Result<(), Box<dyn Error>> {
+    let engine = payment_engine::open_engine(options)?;
+    let listener = TcpListener::bind(address)?;
+    info!("Listening for HTTP on {}", listener.local_addr()?);
+    payment_engine::serve_http(listener, engine)
 }
 
-fn process_deposit(account: &mut ClientAccount, transaction: ReadTransaction) {
-    account.available += transaction.amount.unwrap();
-    account.transactions.insert(
-        transaction.tx,
-        InternalTransaction {
-            amount: transaction.amount.unwrap(),
-            kind: transaction.kind,
-        },
-    );
-}
-
-fn process_dispute(account: &mut ClientAccount, transaction: ReadTransaction) {
-    match account.transactions.get(&transaction.tx) {
-        Some(reference_transaction) => {
-            if account.disputes.contains(&transaction.tx) {
-                warn!("Rejecting dispute. Referenced transaction already in dispute. Referenced Transaction ID: {}", &transaction.tx);
-                return;
-            }
-            if account.completed_disputes.contains(&transaction.tx) {
-                warn!("Rejecting dispute. Cannot dispute a transaction more than once. Referenced Transaction ID: {}", &transaction.tx);
-                return;
-            }
-
-            account.held += reference_transaction.amount;
-            account.available -= reference_transaction.amount;
-            account.disputes.insert(transaction.tx);
+fn parse_command(args: impl Iterator<Item = String>) -> Result<Command, Box<dyn Error>> {
+    let mut args = args.peekable();
+    match args.peek().map(String::as_str) {
+        Some("replay") => {
+            args.next();
+            let (event_log, options) = parse_single_arg(
+                args,
+                "replay",
+                &REPLAY_UNSUPPORTED,
+                "replay expects an event log path",
+            )?;
+            Ok(Command::Replay(event_log, options))
         }
-        None => {
-            info!("Rejecting dispute. Referenced transaction not found. Referenced Transaction ID: {}", &transaction.tx);
+        Some("serve") => {
+            args.next();
+            let (address, options) = parse_single_arg(
+                args,
+                "servers",
+                &SERVER_UNSUPPORTED,
+                "expected an address to listen on",
+            )?;
+            Ok(Command::Serve(address, options))
         }
-    }
-}
-
-fn process_resolve(account: &mut ClientAccount, transaction: ReadTransaction) {
-    let reference_transaction_disputed = account.disputes.contains(&transaction.tx);
-    if reference_transaction_disputed {
-        match account.transactions.get_mut(&transaction.tx) {
-            Some(reference_transaction) => {
-                account.held -= reference_transaction.amount;
-                account.available += reference_transaction.amount;
-                account.disputes.remove(&transaction.tx);
-                account.completed_disputes.insert(transaction.tx);
-            }
-            None => {
-                error!("Rejecting resolve. Referenced transaction not found. Referenced Transaction ID: {}", &transaction.tx);
-            }
+        Some("serve-http") => {
+            args.next();
+            let (address, options) = parse_single_arg(
+                args,
+                "servers",
+                &SERVER_UNSUPPORTED,
+                "expected an address to listen on",
+            )?;
+            Ok(Command::ServeHttp(address, options))
         }
-    } else {
-        info!(
-            "Rejecting resolve. Disputed transaction not found. Referenced Transaction ID: {}",
-            &transaction.tx
-        );
+        _ => Ok(Command::Run(parse_args(args)?)),
     }
 }
 
-fn process_withdrawal(account: &mut ClientAccount, transaction: ReadTransaction) {
-    // Assumption - cannot dispute withdrawals that do not happen. This means
-    // failed withdrawals are not saved in the transaction log.
-    let transaction_amount = transaction.amount.unwrap();
-
-    if transaction_amount <= account.available {
-        account.available -= transaction_amount;
-        account.transactions.insert(
-            transaction.tx,
-            InternalTransaction {
-                amount: transaction_amount,
-                kind: transaction.kind,
-            },
-        );
-    } else {
-        info!(
-            "Rejecting withdrawal. Cannot withdraw more than available amount. Transaction ID: {}",
-            &transaction.tx
-        );
+// Replay and the servers take one argument, the event log or the address to
+// listen on, where a batch run takes its inputs. Options that have no effect
+// on them are refused rather than ignored.
+fn parse_single_arg(
+    args: impl Iterator<Item = String>,
+    command: &str,
+    unsupported: &[&str],
+    missing: &str,
+) -> Result<(String, RunOptions), Box<dyn Error>> {
+    let args: Vec<String> = args.collect();
+    if let Some(option) = args.iter().find(|arg| unsupported.contains(&arg.as_str())) {
+        return Err(format!("{} is not supported by {}", option, command).into());
     }
-}
-
-fn process_transaction(
-    client_accounts: &mut HashMap<u16, ClientAccount>,
-    transaction: ReadTransaction,
-) {
-    let client_id = transaction.client;
-
-    let account: &mut ClientAccount = client_accounts.entry(client_id).or_insert(ClientAccount {
-        available: Decimal::new(0, 4),
-        client: client_id,
-        completed_disputes: HashSet::new(),
-        disputes: HashSet::new(),
-        held: Decimal::new(0, 4),
-        locked: false,
-        total: Decimal::new(0, 4),
-        transactions: HashMap::new(),
-    });
-
-    // Assumption - once the account is locked we're 100% locked for this
-    // client. No further transactions are processed.
-    //
-    // In a real life situation we would probably have to still process
-    // disputes, chargebacks, and resolves.
-    if account.locked {
-        info!(
-            "Rejecting transaction. Account locked. Referenced Transaction ID: {}",
-            &transaction.tx
-        );
-        return;
-    }
-
-    if (transaction.kind == TransactionType::Withdrawal
-        || transaction.kind == TransactionType::Deposit)
-        && account.transactions.contains_key(&transaction.tx)
-    {
-        info!(
-            "Rejecting transaction. Duplicate transaction. Transaction ID: {}",
-            &transaction.tx
-        );
-        return;
-    }
-
-    match transaction.kind {
-        TransactionType::Chargeback => process_chargeback(account, transaction),
-        TransactionType::Deposit => process_deposit(account, transaction),
-        TransactionType::Dispute => process_dispute(account, transaction),
-        TransactionType::Resolve => process_resolve(account, transaction),
-        TransactionType::Withdrawal => process_withdrawal(account, transaction),
+    let mut options = parse_args(args.into_iter())?;
+    if options.inputs.len() != 1 {
+        return Err(missing.into());
     }
+    let arg = options.inputs.remove(0);
+    Ok((arg, options))
 }
 
-// Handling the record manually allows for robust CSV handling
-// Serde automatic deserialization didn't like Option<Decimal> (or I couldn't
-// get it work anyway)
-// Serde tuple deserialization was a little better, but if the line ending in
-// the CSV didn't have a comma it would throw.
-//
-// If this were a production system I'd add position information when logging these errors
-// https://docs.rs/csv/latest/csv/struct.Reader.html#method.position
-fn deserialize_transaction(record: StringRecord) -> Option<ReadTransaction> {
-    let kind = if !record.is_empty() {
-        match TransactionType::from_str(&record[0]) {
-            Ok(val) => val,
-            Err(err) => {
-                error!(
-                    "Rejecting transaction. Unable to read transaction type from CSV. Error: {:?}",
-                    err
-                );
-                return None;
-            }
-        }
-    } else {
-        error!(
-            "Rejecting transaction. Unable to read transaction type from CSV. Not enough fields.",
-        );
-        return None;
-    };
-    let client = if record.len() > 1 {
-        match record[1].parse::<u16>() {
-            Ok(val) => val,
-            Err(err) => {
-                error!(
-                    "Rejecting transaction. Unable to read client from CSV. Error: {:?}",
-                    err
-                );
-                return None;
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<RunOptions, Box<dyn Error>> {
+    let mut options = RunOptions::default();
+    // Flags override the policy file, wherever they come on the command line.
+    let mut policy_path = None;
+    let mut locked_policy: Option<LockedPolicy> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rejects" => options.rejects = Some(value_for(&arg, args.next())?),
+            "--snapshot" => options.snapshot = Some(value_for(&arg, args.next())?),
+            "--event-log" => options.event_log = Some(value_for(&arg, args.next())?),
+            "--disk-store" => options.disk_store = Some(value_for(&arg, args.next())?),
+            "--cache-size" => options.cache_size = Some(value_for(&arg, args.next())?.parse()?),
+            "--threads" => options.threads = Some(value_for(&arg, args.next())?.parse()?),
+            "--output-format" => options.output_format = value_for(&arg, args.next())?.parse()?,
+            "--precision" => options.precision.scale = value_for(&arg, args.next())?.parse()?,
+            "--rounding" => options.precision.rounding = value_for(&arg, args.next())?.parse()?,
+            "--rates" => options.rates = Some(value_for(&arg, args.next())?),
+            "--base-currency" => {
+                let value = value_for(&arg, args.next())?;
+                match value.parse() {
+                    Ok(currency) => options.base_currency = Some(currency),
+                    Err(_) => return Err(format!("invalid currency {}", value).into()),
+                }
             }
-        }
-    } else {
-        error!("Rejecting transaction. Unable to read client from CSV. Not enough fields.",);
-        return None;
-    };
-    let tx = if record.len() > 2 {
-        match record[2].parse::<u32>() {
-            Ok(val) => val,
-            Err(err) => {
-                error!(
-                    "Rejecting transaction. Unable to read tx from CSV. Error: {:?}",
-                    err
-                );
-                return None;
+            "--spread" => options.spread = Some(value_for(&arg, args.next())?.parse()?),
+            "--fees" => options.fees = Some(value_for(&arg, args.next())?),
+            "--house-account" => {
+                options.house_account = Some(value_for(&arg, args.next())?.parse()?)
             }
-        }
-    } else {
-        error!("Rejecting transaction. Unable to read tx from CSV. Not enough fields.",);
-        return None;
-    };
-    let amount = if record.len() > 3 {
-        match Decimal::from_str(&record[3]) {
-            Ok(val) => Some(val),
-            Err(err) => {
-                if kind == TransactionType::Deposit || kind == TransactionType::Withdrawal {
-                    error!(
-                        "Rejecting transaction. Unable to read amount from CSV. Error: {:?}",
-                        err
-                    );
-                    return None;
-                } else {
-                    None
-                }
+            "--fee-ledger" => options.fee_ledger = Some(value_for(&arg, args.next())?),
+            "--policy" => policy_path = Some(value_for(&arg, args.next())?),
+            "--locked-policy" => locked_policy = Some(value_for(&arg, args.next())?.parse()?),
+            "--dispute-window" => {
+                options.dispute_window = Some(value_for(&arg, args.next())?.parse()?)
             }
+            "--extended" => options.extended = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg).into()),
+            _ => options.inputs.push(arg),
         }
-    } else {
-        None
-    };
-
-    Some(ReadTransaction {
-        kind,
-        client,
-        tx,
-        amount,
-    })
-}
-
-fn do_run(input_filename: &String, stdout: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
-    let mut client_accounts: HashMap<u16, ClientAccount> = HashMap::new();
+    }
 
-    let mut reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .flexible(true)
-        .from_path(input_filename)?;
+    if let Some(path) = policy_path {
+        options.policy = Policy::from_path(&path)?;
+    }
+    if let Some(locked) = locked_policy {
+        options.policy.locked = locked;
+    }
 
-    for result in reader.records() {
-        match result {
-            Ok(r) => {
-                match deserialize_transaction(r) {
-                    Some(transaction) => process_transaction(&mut client_accounts, transaction),
-                    None => {
-                        continue;
-                    }
-                };
-            }
-            Err(err) => {
-                error!(
-                    "Rejecting transaction. Unable to read transaction from CSV. Error: {}",
-                    err
-                );
-            }
+    if options.precision.scale > MAX_SCALE {
+        return Err(format!("--precision cannot be more than {}", MAX_SCALE).into());
+    }
+    if let Some(spread) = options.spread {
+        if spread < Decimal::ZERO || spread >= Decimal::ONE {
+            return Err("--spread must be at least 0 and less than 1".into());
         }
     }
-
-    let mut writer = csv::Writer::from_writer(stdout);
-    for val in client_accounts.values_mut() {
-        val.total = val.available + val.held;
-        writer.serialize(val).unwrap();
+    if options.inputs.is_empty() {
+        return Err(From::from("expected 1 argument, but got none"));
     }
+    Ok(options)
+}
 
-    Ok(())
+fn value_for(option: &str, value: Option<String>) -> Result<String, Box<dyn Error>> {
+    value.ok_or_else(|| format!("{} expects a value", option).into())
 }
@@ -1,47 +1,224 @@
 use rust_decimal::prelude::*;
-use serde::Serialize;
-use std::collections::HashMap;
-use std::collections::HashSet;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::str::FromStr;
 
-#[derive(Debug, Serialize)]
+use crate::precision::DEFAULT_SCALE;
+
+#[derive(Debug)]
 pub struct ClientAccount {
     pub client: u16,
-    pub available: Decimal,
-    pub held: Decimal,
-    pub total: Decimal,
+    /// One balance per currency the client has used. `None` is the currency
+    /// of rows that do not name one.
+    pub balances: BTreeMap<Option<Currency>, Balance>,
     pub locked: bool,
-    #[serde(skip_serializing)]
     pub disputes: HashSet<u32>,
-    #[serde(skip_serializing)]
     pub completed_disputes: HashSet<u32>,
-    #[serde(skip_serializing)]
-    pub transactions: HashMap<u32, InternalTransaction>,
+    /// The latest timestamp of the client's accepted transactions, if any
+    /// carried one.
+    pub last_timestamp: Option<u64>,
+}
+
+impl ClientAccount {
+    pub fn new(client: u16) -> ClientAccount {
+        ClientAccount {
+            balances: BTreeMap::new(),
+            client,
+            completed_disputes: HashSet::new(),
+            disputes: HashSet::new(),
+            last_timestamp: None,
+            locked: false,
+        }
+    }
+
+    /// The balance held in `currency`, which is zero if the client has never
+    /// used it.
+    pub fn balance(&self, currency: Option<Currency>) -> Balance {
+        self.balances
+            .get(&currency)
+            .copied()
+            .unwrap_or_else(Balance::new)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Balance {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+impl Balance {
+    pub fn new() -> Balance {
+        Balance::zero(DEFAULT_SCALE)
+    }
+
+    /// An empty balance written with `scale` decimal places.
+    pub fn zero(scale: u32) -> Balance {
+        Balance {
+            available: Decimal::new(0, scale),
+            held: Decimal::new(0, scale),
+            total: Decimal::new(0, scale),
+        }
+    }
+}
+
+impl Default for Balance {
+    fn default() -> Balance {
+        Balance::new()
+    }
+}
+
+/// A three letter currency code such as `USD`. Always upper case.
+#[derive(Clone, Copy, Debug, Hash, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // Only ever built from ASCII letters.
+        std::str::from_utf8(&self.0).unwrap()
+    }
+
+    pub(crate) fn as_bytes(&self) -> [u8; 3] {
+        self.0
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; 3]) -> Option<Currency> {
+        std::str::from_utf8(&bytes).ok()?.parse().ok()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<Currency, Self::Err> {
+        match input.as_bytes() {
+            &[a, b, c] if input.bytes().all(|byte| byte.is_ascii_alphabetic()) => {
+                Ok(Currency([a, b, c].map(|byte| byte.to_ascii_uppercase())))
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
 }
 
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Currency, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        input
+            .parse()
+            .map_err(|_| de::Error::custom(format!("invalid currency {}", input)))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct InternalTransaction {
     pub kind: TransactionType,
     pub amount: Decimal,
+    /// Disputes hold and release funds in this currency.
+    pub currency: Option<Currency>,
+    /// The exchange rate `amount` was converted into the base currency at,
+    /// if it was converted.
+    #[serde(default)]
+    pub rate: Option<Decimal>,
+    /// When the transaction happened, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// A withdrawal that was refused, kept only so it can be disputed.
+    #[serde(default)]
+    pub failed: bool,
+}
+
+impl InternalTransaction {
+    /// The funds a dispute of this transaction holds. A failed withdrawal
+    /// moved nothing, so disputing it holds nothing.
+    pub fn disputed_amount(&self) -> Decimal {
+        if self.failed {
+            Decimal::ZERO
+        } else {
+            self.amount
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Deserialize)]
 pub struct ReadTransaction {
+    #[serde(rename = "type")]
     pub kind: TransactionType,
     pub client: u16,
     pub tx: u32,
+    #[serde(default)]
     pub amount: Option<Decimal>,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// The client a transfer pays.
+    #[serde(default)]
+    pub destination: Option<u16>,
+    /// When the transaction happened, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// The operator who froze or unlocked the account.
+    #[serde(default)]
+    pub operator: Option<u32>,
 }
 
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Chargeback,
     Deposit,
     Dispute,
+    /// Locks an account. Sent by an operator, not a client.
+    Freeze,
     Resolve,
+    Transfer,
+    /// Unlocks an account. Sent by an operator, not a client.
+    Unlock,
     Withdrawal,
 }
 
+impl TransactionType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Deposit => "deposit",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Freeze => "freeze",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Withdrawal => "withdrawal",
+        }
+    }
+
+    /// Whether the transaction brings a new transaction ID, rather than
+    /// referencing an earlier one or acting on the account.
+    pub fn is_new_tx(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Deposit | TransactionType::Transfer | TransactionType::Withdrawal
+        )
+    }
+
+    /// Whether this is an operator action on the account rather than a
+    /// client's transaction.
+    pub fn is_operator(&self) -> bool {
+        matches!(self, TransactionType::Freeze | TransactionType::Unlock)
+    }
+}
+
 impl FromStr for TransactionType {
     type Err = ();
 
@@ -50,7 +227,10 @@ impl FromStr for TransactionType {
             "chargeback" => Ok(TransactionType::Chargeback),
             "deposit" => Ok(TransactionType::Deposit),
             "dispute" => Ok(TransactionType::Dispute),
+            "freeze" => Ok(TransactionType::Freeze),
             "resolve" => Ok(TransactionType::Resolve),
+            "transfer" => Ok(TransactionType::Transfer),
+            "unlock" => Ok(TransactionType::Unlock),
             "withdrawal" => Ok(TransactionType::Withdrawal),
             _ => Err(()),
         }
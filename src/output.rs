@@ -0,0 +1,190 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::error::Error;
+use std::io;
+use std::str::FromStr;
+
+use crate::model::*;
+
+/// How account balances are written at the end of a run.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    Ndjson,
+    /// Aligned columns for people to read.
+    Table,
+}
+
+impl OutputFormat {
+    pub fn is_json(&self) -> bool {
+        matches!(self, OutputFormat::Json | OutputFormat::Ndjson)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<OutputFormat, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("unknown output format {}", input)),
+        }
+    }
+}
+
+/// One output row: a client's balance in one currency, with the extended
+/// fields that only the JSON formats carry.
+#[derive(Serialize)]
+pub(crate) struct AccountRow {
+    pub client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    /// The client's transactions currently in dispute, in ID order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disputes: Option<Vec<u32>>,
+}
+
+impl AccountRow {
+    /// One row per currency `account` holds, or a single zero row with `scale`
+    /// decimal places for an account that has no balances yet.
+    pub fn rows(account: &ClientAccount, scale: u32, extended: bool) -> Vec<AccountRow> {
+        let disputes = if extended {
+            let mut disputes: Vec<u32> = account.disputes.iter().copied().collect();
+            disputes.sort_unstable();
+            Some(disputes)
+        } else {
+            None
+        };
+        let mut balances: Vec<(Option<Currency>, Balance)> = account
+            .balances
+            .iter()
+            .map(|(&currency, &balance)| (currency, balance))
+            .collect();
+        if balances.is_empty() {
+            balances.push((None, Balance::zero(scale)));
+        }
+        balances
+            .into_iter()
+            .map(|(currency, balance)| AccountRow {
+                client: account.client,
+                currency,
+                available: balance.available,
+                held: balance.held,
+                total: balance.total,
+                locked: account.locked,
+                disputes: disputes.clone(),
+            })
+            .collect()
+    }
+
+    fn cells(&self, with_currency: bool) -> Vec<String> {
+        let mut cells = vec![self.client.to_string()];
+        if with_currency {
+            cells.push(
+                self.currency
+                    .map_or(String::new(), |currency| currency.to_string()),
+            );
+        }
+        cells.extend([
+            self.available.to_string(),
+            self.held.to_string(),
+            self.total.to_string(),
+            self.locked.to_string(),
+        ]);
+        cells
+    }
+}
+
+fn header(with_currency: bool) -> Vec<String> {
+    let mut header = vec![String::from("client")];
+    if with_currency {
+        header.push(String::from("currency"));
+    }
+    header.extend([
+        String::from("available"),
+        String::from("held"),
+        String::from("total"),
+        String::from("locked"),
+    ]);
+    header
+}
+
+/// Writes `accounts` in `format`, one row per client and currency, with
+/// balances at `scale` decimal places. `extended` adds the open disputes to
+/// each row in the JSON formats and is ignored by the others.
+///
+/// The CSV and table formats only get a `currency` column when some balance
+/// is in a named currency, so single currency input keeps its usual layout.
+pub(crate) fn write_accounts<'a>(
+    accounts: impl Iterator<Item = &'a ClientAccount>,
+    scale: u32,
+    format: OutputFormat,
+    extended: bool,
+    out: &mut dyn io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let extended = extended && format.is_json();
+    let rows: Vec<AccountRow> = accounts
+        .flat_map(|account| AccountRow::rows(account, scale, extended))
+        .collect();
+    let with_currency = rows.iter().any(|row| row.currency.is_some());
+
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(header(with_currency))?;
+            for row in &rows {
+                writer.write_record(row.cells(with_currency))?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut *out, &rows)?;
+            out.write_all(b"\n")?;
+            Ok(())
+        }
+        OutputFormat::Ndjson => {
+            for row in &rows {
+                serde_json::to_writer(&mut *out, row)?;
+                out.write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        OutputFormat::Table => write_table(&rows, with_currency, out),
+    }
+}
+
+fn write_table(
+    rows: &[AccountRow],
+    with_currency: bool,
+    out: &mut dyn io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut table = vec![header(with_currency)];
+    table.extend(rows.iter().map(|row| row.cells(with_currency)));
+
+    let mut widths = vec![0; table[0].len()];
+    for row in &table {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for row in &table {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::engine::Rejection;
+use crate::model::*;
+
+/// What a locked account still accepts besides an operator's freeze or
+/// unlock. Deposits, withdrawals and transfers are always refused.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockedPolicy {
+    /// Nothing at all.
+    #[default]
+    Frozen,
+    /// Resolves and chargebacks, so disputes already open can be settled.
+    SettleDisputes,
+    /// Resolves, chargebacks and new disputes.
+    Disputes,
+}
+
+impl LockedPolicy {
+    pub fn allows(&self, kind: TransactionType) -> bool {
+        match kind {
+            TransactionType::Freeze | TransactionType::Unlock => true,
+            TransactionType::Chargeback | TransactionType::Resolve => *self != LockedPolicy::Frozen,
+            TransactionType::Dispute => *self == LockedPolicy::Disputes,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for LockedPolicy {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<LockedPolicy, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "frozen" => Ok(LockedPolicy::Frozen),
+            "settle-disputes" => Ok(LockedPolicy::SettleDisputes),
+            "disputes" => Ok(LockedPolicy::Disputes),
+            _ => Err(format!("unknown locked account policy {}", input)),
+        }
+    }
+}
+
+/// Which transactions a deposit, withdrawal or transfer's ID must be unique
+/// among.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TxIdScope {
+    /// The same client's.
+    Client,
+    /// Every client's.
+    #[default]
+    Global,
+}
+
+/// The rules the engine processes transactions by. The default is the
+/// behaviour described under assumptions in the README.
+///
+/// A policy file is TOML if its name ends in `.toml`, and JSON otherwise.
+/// Every field is optional and falls back to its default.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Whether a transaction may only be disputed once.
+    pub dispute_once: bool,
+    /// Whether withdrawals refused for insufficient funds are kept so they
+    /// can be disputed. Disputing one holds no funds.
+    pub dispute_failed_withdrawals: bool,
+    pub locked: LockedPolicy,
+    pub tx_ids: TxIdScope,
+    /// Days after a transaction that it can still be disputed. No limit when
+    /// unset.
+    pub dispute_window: Option<u64>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            dispute_once: true,
+            dispute_failed_withdrawals: false,
+            locked: LockedPolicy::default(),
+            tx_ids: TxIdScope::default(),
+            dispute_window: None,
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_path(path: &str) -> Result<Policy, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        match Path::new(path).extension() {
+            Some(extension) if extension == "toml" => Ok(toml::from_str(&contents)?),
+            _ => Ok(serde_json::from_str(&contents)?),
+        }
+    }
+
+    // Whether a transaction rejected this way still changed the transaction
+    // store.
+    pub(crate) fn records_failure(&self, kind: TransactionType, rejection: Rejection) -> bool {
+        self.dispute_failed_withdrawals
+            && kind == TransactionType::Withdrawal
+            && rejection == Rejection::InsufficientFunds
+    }
+}
@@ -0,0 +1,89 @@
+use rust_decimal::prelude::*;
+use std::str::FromStr;
+
+use crate::model::*;
+
+/// Decimal places amounts and balances are kept to unless configured
+/// otherwise.
+pub const DEFAULT_SCALE: u32 = 4;
+
+/// What to do with an amount that has more decimal places than allowed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Rounding {
+    /// Reject the transaction.
+    #[default]
+    Reject,
+    /// Round half to even.
+    Bankers,
+    /// Round half away from zero.
+    HalfUp,
+    /// Drop the extra places.
+    Truncate,
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Rounding, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "reject" => Ok(Rounding::Reject),
+            "bankers" => Ok(Rounding::Bankers),
+            "half-up" => Ok(Rounding::HalfUp),
+            "truncate" => Ok(Rounding::Truncate),
+            _ => Err(format!("unknown rounding {}", input)),
+        }
+    }
+}
+
+/// How many decimal places the engine keeps, and how it gets amounts there.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Precision {
+    pub scale: u32,
+    pub rounding: Rounding,
+}
+
+impl Default for Precision {
+    fn default() -> Precision {
+        Precision {
+            scale: DEFAULT_SCALE,
+            rounding: Rounding::default(),
+        }
+    }
+}
+
+impl Precision {
+    /// Brings `amount` within `scale` decimal places, or returns `None` if it
+    /// has more and the rounding is `Reject`. Trailing zeros do not count, so
+    /// `1.50000` is fine at four places.
+    pub fn normalize(&self, amount: Decimal) -> Option<Decimal> {
+        if amount.normalize().scale() <= self.scale {
+            return Some(amount);
+        }
+        match self.rounding {
+            Rounding::Reject => None,
+            _ => Some(self.round(amount)),
+        }
+    }
+
+    /// Rounds `amount` to `scale` decimal places. Used for amounts the engine
+    /// works out itself, such as currency conversions, which are rounded
+    /// half to even when the engine is set to reject.
+    pub fn round(&self, amount: Decimal) -> Decimal {
+        let strategy = match self.rounding {
+            Rounding::Reject | Rounding::Bankers => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+        };
+        amount.round_dp_with_strategy(self.scale, strategy)
+    }
+
+    /// Writes every balance of `account` with exactly `scale` decimal places.
+    /// Balances only ever come from normalized amounts, so nothing is lost.
+    pub fn rescale(&self, account: &mut ClientAccount) {
+        for balance in account.balances.values_mut() {
+            balance.available.rescale(self.scale);
+            balance.held.rescale(self.scale);
+            balance.total.rescale(self.scale);
+        }
+    }
+}
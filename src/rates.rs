@@ -0,0 +1,84 @@
+use rust_decimal::prelude::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+
+use crate::model::*;
+
+/// Exchange rates into the base currency that accounts are settled in.
+///
+/// The rate table is a CSV file with a `currency,rate` header and one row per
+/// currency, where `rate` is how much one unit of that currency is worth in
+/// the base currency. The spread is taken against the client: deposits are
+/// converted at `rate * (1 - spread)` and withdrawals at `rate * (1 + spread)`.
+#[derive(Clone, Debug, Default)]
+pub struct RateTable {
+    base: Option<Currency>,
+    rates: HashMap<Currency, Decimal>,
+    spread: Decimal,
+}
+
+impl RateTable {
+    pub fn new() -> RateTable {
+        RateTable::default()
+    }
+
+    pub fn from_path(path: &str) -> Result<RateTable, Box<dyn Error>> {
+        RateTable::from_reader(File::open(path)?)
+    }
+
+    pub fn from_reader(reader: impl io::Read) -> Result<RateTable, Box<dyn Error>> {
+        let mut table = RateTable::new();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |position| position.line());
+            let (currency, rate) = match (record.get(0), record.get(1)) {
+                (Some(currency), Some(rate)) => (currency, rate),
+                _ => return Err(format!("rate table line {} is incomplete", line).into()),
+            };
+            let currency = Currency::from_str(currency)
+                .map_err(|_| format!("rate table line {} has an invalid currency", line))?;
+            let rate = Decimal::from_str(rate)
+                .ok()
+                .filter(|rate| *rate > Decimal::ZERO)
+                .ok_or_else(|| format!("rate table line {} has an invalid rate", line))?;
+            table.set_rate(currency, rate);
+        }
+        Ok(table)
+    }
+
+    /// Names the currency accounts are settled in. Rows in it are never
+    /// converted.
+    pub fn set_base(&mut self, base: Currency) {
+        self.base = Some(base);
+    }
+
+    pub fn set_rate(&mut self, currency: Currency, rate: Decimal) {
+        self.rates.insert(currency, rate);
+    }
+
+    /// Sets the spread as a fraction, so `0.01` is one percent.
+    pub fn set_spread(&mut self, spread: Decimal) {
+        self.spread = spread;
+    }
+
+    /// The rate to convert a `kind` transaction in `currency` at, with the
+    /// spread applied. Transfers stay between clients of the engine and are
+    /// converted without a spread. `None` if there is no rate for the
+    /// currency.
+    pub fn rate(&self, kind: TransactionType, currency: Currency) -> Option<Decimal> {
+        if Some(currency) == self.base {
+            return Some(Decimal::ONE);
+        }
+        let rate = *self.rates.get(&currency)?;
+        match kind {
+            TransactionType::Transfer => Some(rate),
+            TransactionType::Withdrawal => rate.checked_mul(Decimal::ONE + self.spread),
+            _ => rate.checked_mul(Decimal::ONE - self.spread),
+        }
+    }
+}
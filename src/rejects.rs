@@ -0,0 +1,62 @@
+use csv::{Position, StringRecord};
+use std::error::Error;
+use std::fs::File;
+use std::io;
+
+/// Writes rejected input rows to a CSV report so they can be fixed and
+/// resubmitted.
+///
+/// Each row holds the source file, line and byte offset, a reason code, and
+/// then the original fields exactly as they were read.
+pub struct RejectsWriter<W: io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl RejectsWriter<File> {
+    pub fn from_path(path: &str) -> Result<RejectsWriter<File>, Box<dyn Error>> {
+        Ok(RejectsWriter::from_writer(File::create(path)?)?)
+    }
+}
+
+impl<W: io::Write> RejectsWriter<W> {
+    pub fn from_writer(writer: W) -> csv::Result<RejectsWriter<W>> {
+        let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(writer);
+        writer.write_record([
+            "file",
+            "line",
+            "byte",
+            "reason",
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "currency",
+            "destination",
+            "timestamp",
+            "operator",
+        ])?;
+        Ok(RejectsWriter { writer })
+    }
+
+    pub fn write(
+        &mut self,
+        source: &str,
+        position: Option<&Position>,
+        reason: &str,
+        record: &StringRecord,
+    ) -> csv::Result<()> {
+        let (line, byte) = position.map_or((0, 0), |position| (position.line(), position.byte()));
+        let mut row = StringRecord::from(vec![
+            source.to_string(),
+            line.to_string(),
+            byte.to_string(),
+            reason.to_string(),
+        ]);
+        row.extend(record.iter());
+        self.writer.write_record(&row)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,78 @@
+use std::error::Error;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::engine::PaymentEngine;
+
+/// Accepts transactions over TCP. Connections that fail to be accepted are
+/// logged and skipped.
+///
+/// Each connection sends transaction rows in the same CSV format as the
+/// input file, one per line and without a header. Every row is answered
+/// with `ok <outcome>` or `rejected <reason>`. A `balances` line is answered
+/// with the current balances as CSV followed by an empty line.
+///
+/// Connections are served on their own threads and share one engine, so
+/// rows from different connections are applied in arrival order.
+pub fn serve(listener: TcpListener, engine: PaymentEngine) -> Result<(), Box<dyn Error>> {
+    let engine = Arc::new(Mutex::new(engine));
+    for stream in listener.incoming() {
+        // A failed accept only loses that connection, not the server.
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Failed to accept connection. Error: {}", err);
+                continue;
+            }
+        };
+        let engine = Arc::clone(&engine);
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(err) = handle_connection(stream, &engine) {
+                warn!("Connection closed. Peer: {:?}. Error: {}", peer, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    engine: &Mutex<PaymentEngine>,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(BufReader::new(stream));
+
+    for (record, parsed) in crate::read_transactions(&mut reader) {
+        if record.len() == 1 && record[0].trim() == "balances" {
+            // Written out after the lock is released, so a slow client does
+            // not hold up the other connections.
+            let mut balances = Vec::new();
+            {
+                let engine = engine.lock().map_err(|_| "payment engine lock poisoned")?;
+                crate::write_accounts(&engine, &mut balances)?;
+            }
+            balances.push(b'\n');
+            writer.write_all(&balances)?;
+        } else {
+            let response = match parsed {
+                Ok(transaction) => {
+                    let mut engine = engine.lock().map_err(|_| "payment engine lock poisoned")?;
+                    match engine.apply(transaction) {
+                        Ok(outcome) => format!("ok {}\n", outcome.as_str()),
+                        Err(rejection) => format!("rejected {}\n", rejection.code()),
+                    }
+                }
+                Err(err) => format!("rejected {}\n", err.code()),
+            };
+            writer.write_all(response.as_bytes())?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
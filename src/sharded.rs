@@ -0,0 +1,241 @@
+use csv::StringRecord;
+use std::error::Error;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::engine::{PaymentEngine, Rejection};
+use crate::model::*;
+use crate::tx_index::TxIndex;
+
+// Transactions are handed to workers in batches to keep channel overhead
+// off the hot path.
+const BATCH_SIZE: usize = 1024;
+
+// Batches queued per worker before `submit` blocks.
+const QUEUE_DEPTH: usize = 16;
+
+struct Job<T> {
+    transaction: ReadTransaction,
+    tag: Option<T>,
+}
+
+enum Message<T> {
+    Batch(Vec<Job<T>>),
+    // Answered once every batch sent before it has been applied.
+    Sync(SyncSender<()>),
+}
+
+type Rejected<T> = Vec<(Rejection, T)>;
+
+/// Spreads transactions across one `PaymentEngine` per worker thread.
+///
+/// Almost every transaction only touches its own client's account, so
+/// routing by client keeps each client's transactions in order on a single
+/// worker and the final balances match a sequential run exactly. A transfer
+/// between clients on different workers waits for both workers to catch up
+/// and is then applied to both engines at once, so it stays in order too.
+/// So does a row that uses a transaction ID a client on another worker has
+/// used, which waits for every worker to catch up.
+///
+/// Transactions can be submitted with a tag, such as the input row they came
+/// from, which is handed back if the transaction is rejected.
+pub struct ShardedEngine<T = StringRecord> {
+    senders: Vec<SyncSender<Message<T>>>,
+    batches: Vec<Vec<Job<T>>>,
+    engines: Vec<Arc<Mutex<PaymentEngine>>>,
+    workers: Vec<JoinHandle<Rejected<T>>>,
+    // The transaction IDs each worker has been sent a deposit, withdrawal or
+    // transfer for.
+    submitted: Vec<TxIndex>,
+    // Rejected transfers between shards, which are applied here rather than
+    // on a worker.
+    rejected: Rejected<T>,
+}
+
+impl ShardedEngine {
+    pub fn shard_for(client: u16, shards: usize) -> usize {
+        client as usize % shards
+    }
+}
+
+impl<T: Send + 'static> ShardedEngine<T> {
+    /// Starts one worker per engine in `shards`. Each engine should only
+    /// hold the clients that `shard_for` routes to it.
+    pub fn new(shards: Vec<PaymentEngine>) -> ShardedEngine<T> {
+        let mut senders = Vec::new();
+        let mut engines = Vec::new();
+        let mut workers = Vec::new();
+        for engine in shards {
+            let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+            let engine = Arc::new(Mutex::new(engine));
+            senders.push(sender);
+            engines.push(engine.clone());
+            workers.push(thread::spawn(move || work(engine, receiver)));
+        }
+        ShardedEngine {
+            batches: senders.iter().map(|_| Vec::new()).collect(),
+            submitted: senders.iter().map(|_| TxIndex::new()).collect(),
+            senders,
+            engines,
+            workers,
+            rejected: Vec::new(),
+        }
+    }
+
+    /// Queues a transaction on its client's worker. If `tag` is given and
+    /// the transaction is rejected, the tag is handed back by `finish`.
+    pub fn submit(&mut self, transaction: ReadTransaction, tag: Option<T>) {
+        let shards = self.senders.len();
+        let shard = ShardedEngine::shard_for(transaction.client, shards);
+        if !transaction.kind.is_operator() {
+            self.settle_tx_id(shard, transaction.tx);
+            if transaction.kind.is_new_tx() {
+                self.submitted[shard].insert(transaction.tx);
+            }
+        }
+        if let (TransactionType::Transfer, Some(destination)) =
+            (transaction.kind, transaction.destination)
+        {
+            let destination_shard = ShardedEngine::shard_for(destination, shards);
+            if destination_shard != shard {
+                self.transfer_across(shard, destination_shard, transaction, tag);
+                return;
+            }
+        }
+
+        self.batches[shard].push(Job { transaction, tag });
+        if self.batches[shard].len() >= BATCH_SIZE {
+            self.send(shard);
+        }
+    }
+
+    fn send(&mut self, shard: usize) {
+        let batch = std::mem::take(&mut self.batches[shard]);
+        // A worker only goes away by panicking, which `finish` reports.
+        let _ = self.senders[shard].send(Message::Batch(batch));
+    }
+
+    // Waits until every batch sent to `shards` so far has been applied.
+    // Returns false if a worker has gone away.
+    fn sync(&mut self, shards: &[usize]) -> bool {
+        let (synced, wait) = mpsc::sync_channel(shards.len());
+        for &shard in shards {
+            self.send(shard);
+            let _ = self.senders[shard].send(Message::Sync(synced.clone()));
+        }
+        drop(synced);
+        shards.iter().all(|_| wait.recv().is_ok())
+    }
+
+    // Each worker's engine only sees the transaction IDs its own clients
+    // have used. When a row on `shard` uses an ID that a client on another
+    // worker has used too, every worker first catches up to this row. If one
+    // of them kept the ID, it is added to this worker's index, so the row is
+    // checked against exactly the IDs a sequential run would have seen.
+    fn settle_tx_id(&mut self, shard: usize, tx: u32) {
+        let elsewhere = self
+            .submitted
+            .iter()
+            .enumerate()
+            .any(|(other, submitted)| other != shard && submitted.contains(tx));
+        let all: Vec<usize> = (0..self.senders.len()).collect();
+        if !elsewhere || !self.sync(&all) {
+            return;
+        }
+
+        let mut kept = false;
+        for (other, engine) in self.engines.iter().enumerate() {
+            if other != shard {
+                match engine.lock() {
+                    Ok(engine) => kept |= engine.tx_index.contains(tx),
+                    Err(_) => return,
+                }
+            }
+        }
+        if kept {
+            if let Ok(engine) = self.engines[shard].lock() {
+                engine.tx_index.insert(tx);
+            }
+        }
+    }
+
+    fn transfer_across(
+        &mut self,
+        shard: usize,
+        destination_shard: usize,
+        transaction: ReadTransaction,
+        tag: Option<T>,
+    ) {
+        if !self.sync(&[shard, destination_shard]) {
+            return;
+        }
+
+        // Both workers are idle until they are sent more work.
+        let (mut engine, mut destination) = match (
+            self.engines[shard].lock(),
+            self.engines[destination_shard].lock(),
+        ) {
+            (Ok(engine), Ok(destination)) => (engine, destination),
+            _ => return,
+        };
+        if let (Err(rejection), Some(tag)) =
+            (engine.apply_across(&mut destination, transaction), tag)
+        {
+            self.rejected.push((rejection, tag));
+        }
+    }
+
+    /// Waits for every worker and merges the shards back into one engine.
+    /// Also returns the tags of rejected transactions.
+    pub fn finish(mut self) -> Result<(PaymentEngine, Rejected<T>), Box<dyn Error>> {
+        for shard in 0..self.senders.len() {
+            self.send(shard);
+        }
+        self.senders.clear();
+
+        let mut rejected = self.rejected;
+        for worker in self.workers {
+            let shard_rejected = worker
+                .join()
+                .map_err(|_| "payment engine worker panicked")?;
+            rejected.extend(shard_rejected);
+        }
+
+        let mut merged: Option<PaymentEngine> = None;
+        for engine in self.engines {
+            let engine = Arc::try_unwrap(engine)
+                .ok()
+                .and_then(|engine| engine.into_inner().ok())
+                .ok_or("payment engine worker panicked")?;
+            match merged.as_mut() {
+                Some(merged) => merged.absorb(engine)?,
+                None => merged = Some(engine),
+            }
+        }
+        Ok((merged.unwrap_or_default(), rejected))
+    }
+}
+
+fn work<T>(engine: Arc<Mutex<PaymentEngine>>, receiver: Receiver<Message<T>>) -> Rejected<T> {
+    let mut rejected = Vec::new();
+    for message in receiver {
+        match message {
+            Message::Batch(batch) => {
+                let mut engine = match engine.lock() {
+                    Ok(engine) => engine,
+                    Err(_) => break,
+                };
+                for job in batch {
+                    if let (Err(rejection), Some(tag)) = (engine.apply(job.transaction), job.tag) {
+                        rejected.push((rejection, tag));
+                    }
+                }
+            }
+            Message::Sync(synced) => {
+                let _ = synced.send(());
+            }
+        }
+    }
+    rejected
+}
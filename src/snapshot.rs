@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use rust_decimal::Decimal;
+
+use crate::engine::PaymentEngine;
+use crate::model::*;
+
+/// Bumped whenever the snapshot layout changes. Older snapshots are refused
+/// rather than guessed at.
+pub const SNAPSHOT_VERSION: u32 = 6;
+
+// A snapshot is one JSON document per line: a header, then every account,
+// then every stored transaction. Reading and writing it streams, so the
+// transaction history never has to fit in memory twice.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    // Decimal places the balances were kept to.
+    scale: u32,
+}
+
+// Borrowed view of a `ClientAccount` so writing a snapshot does not copy it.
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RecordRef<'a> {
+    Account(AccountRef<'a>),
+    Transaction(StoredTransaction),
+}
+
+#[derive(Serialize)]
+struct AccountRef<'a> {
+    client: u16,
+    balances: Vec<BalanceState>,
+    locked: bool,
+    disputes: &'a HashSet<u32>,
+    completed_disputes: &'a HashSet<u32>,
+    last_timestamp: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Record {
+    Account(AccountState),
+    Transaction(StoredTransaction),
+}
+
+#[derive(Deserialize)]
+struct AccountState {
+    client: u16,
+    balances: Vec<BalanceState>,
+    locked: bool,
+    disputes: HashSet<u32>,
+    completed_disputes: HashSet<u32>,
+    last_timestamp: Option<u64>,
+}
+
+// The total is left out and worked out again on load.
+#[derive(Serialize, Deserialize)]
+struct BalanceState {
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredTransaction {
+    client: u16,
+    tx: u32,
+    transaction: InternalTransaction,
+}
+
+impl PaymentEngine {
+    /// Writes the complete engine state, including open disputes and the
+    /// transaction history needed to dispute later.
+    pub fn write_snapshot(&mut self, mut writer: impl Write) -> Result<(), Box<dyn Error>> {
+        write_line(
+            &mut writer,
+            &Header {
+                version: SNAPSHOT_VERSION,
+                scale: self.precision.scale,
+            },
+        )?;
+        for account in self.client_accounts.values() {
+            write_line(
+                &mut writer,
+                &RecordRef::Account(AccountRef {
+                    client: account.client,
+                    balances: account
+                        .balances
+                        .iter()
+                        .map(|(&currency, balance)| BalanceState {
+                            currency,
+                            available: balance.available,
+                            held: balance.held,
+                        })
+                        .collect(),
+                    locked: account.locked,
+                    disputes: &account.disputes,
+                    completed_disputes: &account.completed_disputes,
+                    last_timestamp: account.last_timestamp,
+                }),
+            )?;
+        }
+        self.transactions.for_each(&mut |client, tx, transaction| {
+            write_line(
+                &mut writer,
+                &RecordRef::Transaction(StoredTransaction {
+                    client,
+                    tx,
+                    transaction,
+                }),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Loads a snapshot into this engine, which is expected to be empty and
+    /// already set to the precision the snapshot was saved at, or a higher
+    /// one.
+    pub fn read_snapshot(&mut self, reader: impl BufRead) -> Result<(), Box<dyn Error>> {
+        let mut lines = reader.lines();
+        let header: Header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Err("empty snapshot".into()),
+        };
+        if header.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "unsupported snapshot version {}, expected {}",
+                header.version, SNAPSHOT_VERSION
+            )
+            .into());
+        }
+        // Balances and stored amounts can be brought up to a higher scale,
+        // but never rounded down to a lower one.
+        if header.scale > self.precision.scale {
+            return Err(format!(
+                "snapshot keeps {} decimal places, more than the {} configured",
+                header.scale, self.precision.scale
+            )
+            .into());
+        }
+
+        for line in lines {
+            match serde_json::from_str(&line?)? {
+                Record::Account(state) => {
+                    let mut account = ClientAccount {
+                        client: state.client,
+                        balances: state
+                            .balances
+                            .into_iter()
+                            .map(|balance| {
+                                (
+                                    balance.currency,
+                                    Balance {
+                                        available: balance.available,
+                                        held: balance.held,
+                                        total: balance.available + balance.held,
+                                    },
+                                )
+                            })
+                            .collect(),
+                        locked: state.locked,
+                        disputes: state.disputes,
+                        completed_disputes: state.completed_disputes,
+                        last_timestamp: state.last_timestamp,
+                    };
+                    self.precision.rescale(&mut account);
+                    self.client_accounts.insert(account.client, account);
+                }
+                Record::Transaction(stored) => {
+                    self.tx_index.insert(stored.tx);
+                    self.transactions
+                        .insert(stored.client, stored.tx, stored.transaction)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads a snapshot from `path`, or leaves the engine empty if there is
+    /// none yet.
+    pub fn load_snapshot(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        match File::open(path) {
+            Ok(file) => self.read_snapshot(BufReader::new(file)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Saves a snapshot to `path`. The snapshot is written next to it first
+    /// and renamed into place so a crash never leaves a half-written file.
+    pub fn save_snapshot(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let temp_path = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        self.write_snapshot(&mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp_path, Path::new(path))?;
+        Ok(())
+    }
+
+    /// Saves a snapshot and then empties the event log, since the snapshot
+    /// now holds everything the log did.
+    pub fn checkpoint(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        self.save_snapshot(path)?;
+        if let Some(event_log) = self.event_log.as_mut() {
+            event_log.truncate()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_line(writer: &mut impl Write, value: &impl Serialize) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, value)?;
+    writer.write_all(b"\n")
+}
@@ -0,0 +1,294 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+
+use rust_decimal::Decimal;
+
+use crate::model::*;
+
+/// Default number of transactions a `DiskStore` keeps in memory.
+pub const DEFAULT_CACHE_SIZE: usize = 1_000_000;
+
+/// Where the engine keeps deposits and withdrawals so they can be disputed
+/// later. Transactions are keyed by client and transaction ID.
+pub trait TransactionStore: fmt::Debug + Send {
+    fn get(&mut self, client: u16, tx: u32) -> io::Result<Option<InternalTransaction>>;
+
+    fn insert(&mut self, client: u16, tx: u32, transaction: InternalTransaction) -> io::Result<()>;
+
+    /// Visits every stored transaction. Used to write snapshots.
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(u16, u32, InternalTransaction) -> io::Result<()>,
+    ) -> io::Result<()>;
+}
+
+/// Keeps every transaction in a `HashMap`. Fast, but memory grows with the
+/// number of deposits and withdrawals.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    transactions: HashMap<(u16, u32), InternalTransaction>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl TransactionStore for MemoryStore {
+    fn get(&mut self, client: u16, tx: u32) -> io::Result<Option<InternalTransaction>> {
+        Ok(self.transactions.get(&(client, tx)).copied())
+    }
+
+    fn insert(&mut self, client: u16, tx: u32, transaction: InternalTransaction) -> io::Result<()> {
+        self.transactions.insert((client, tx), transaction);
+        Ok(())
+    }
+
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(u16, u32, InternalTransaction) -> io::Result<()>,
+    ) -> io::Result<()> {
+        for (&(client, tx), &transaction) in self.transactions.iter() {
+            f(client, tx, transaction)?;
+        }
+        Ok(())
+    }
+}
+
+// Slot layout: kind (0 means empty), client, amount, currency (all zeros
+// means none), whether there is a rate, rate, whether there is a timestamp,
+// timestamp, whether the transaction failed.
+const SLOT_SIZE: u64 = 1 + 2 + 16 + 3 + 1 + 16 + 1 + 8 + 1;
+
+/// Keeps transactions in a file with a bounded LRU cache in front of it.
+///
+/// The file has one fixed-size slot per transaction ID, so a lookup is a
+/// single seek and read. Slots for unused IDs are never written and take no
+/// space on filesystems with sparse file support. Transaction IDs are meant
+/// to be globally unique, so an ID reused by a second client is rare and is
+/// kept in memory instead.
+///
+/// The file is scratch space for a single run and is truncated when opened.
+/// Use snapshots to carry state between runs.
+#[derive(Debug)]
+pub struct DiskStore {
+    file: File,
+    cache: LruCache,
+    collisions: HashMap<(u16, u32), InternalTransaction>,
+    max_tx: Option<u32>,
+}
+
+impl DiskStore {
+    pub fn create(path: &str, cache_size: usize) -> io::Result<DiskStore> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(DiskStore {
+            file,
+            cache: LruCache::new(cache_size),
+            collisions: HashMap::new(),
+            max_tx: None,
+        })
+    }
+
+    fn read_slot(&mut self, tx: u32) -> io::Result<Option<(u16, InternalTransaction)>> {
+        let mut slot = [0u8; SLOT_SIZE as usize];
+        self.file.seek(SeekFrom::Start(tx as u64 * SLOT_SIZE))?;
+        match self.file.read_exact(&mut slot) {
+            Ok(()) => Ok(decode_slot(&slot)),
+            // Past the end of the file is the same as a hole.
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_slot(
+        &mut self,
+        client: u16,
+        tx: u32,
+        transaction: InternalTransaction,
+    ) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(tx as u64 * SLOT_SIZE))?;
+        self.file.write_all(&encode_slot(client, transaction))?;
+        self.max_tx = self.max_tx.max(Some(tx));
+        Ok(())
+    }
+}
+
+impl TransactionStore for DiskStore {
+    fn get(&mut self, client: u16, tx: u32) -> io::Result<Option<InternalTransaction>> {
+        if let Some(transaction) = self.cache.get(&(client, tx)) {
+            return Ok(Some(transaction));
+        }
+        if let Some(&transaction) = self.collisions.get(&(client, tx)) {
+            return Ok(Some(transaction));
+        }
+        match self.read_slot(tx)? {
+            Some((owner, transaction)) if owner == client => {
+                self.cache.insert((client, tx), transaction);
+                Ok(Some(transaction))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, client: u16, tx: u32, transaction: InternalTransaction) -> io::Result<()> {
+        match self.read_slot(tx)? {
+            Some((owner, _)) if owner != client => {
+                self.collisions.insert((client, tx), transaction);
+            }
+            _ => {
+                self.write_slot(client, tx, transaction)?;
+                self.cache.insert((client, tx), transaction);
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(u16, u32, InternalTransaction) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if let Some(max_tx) = self.max_tx {
+            self.file.seek(SeekFrom::Start(0))?;
+            let mut reader = BufReader::new(&self.file);
+            let mut slot = [0u8; SLOT_SIZE as usize];
+            for tx in 0..=max_tx {
+                reader.read_exact(&mut slot)?;
+                if let Some((client, transaction)) = decode_slot(&slot) {
+                    f(client, tx, transaction)?;
+                }
+            }
+        }
+        for (&(client, tx), &transaction) in self.collisions.iter() {
+            f(client, tx, transaction)?;
+        }
+        Ok(())
+    }
+}
+
+fn encode_slot(client: u16, transaction: InternalTransaction) -> [u8; SLOT_SIZE as usize] {
+    let mut slot = [0u8; SLOT_SIZE as usize];
+    slot[0] = match transaction.kind {
+        TransactionType::Chargeback => 1,
+        TransactionType::Deposit => 2,
+        TransactionType::Dispute => 3,
+        TransactionType::Resolve => 4,
+        TransactionType::Withdrawal => 5,
+        TransactionType::Transfer => 6,
+        TransactionType::Freeze => 7,
+        TransactionType::Unlock => 8,
+    };
+    slot[1..3].copy_from_slice(&client.to_le_bytes());
+    slot[3..19].copy_from_slice(&transaction.amount.serialize());
+    if let Some(currency) = transaction.currency {
+        slot[19..22].copy_from_slice(&currency.as_bytes());
+    }
+    if let Some(rate) = transaction.rate {
+        slot[22] = 1;
+        slot[23..39].copy_from_slice(&rate.serialize());
+    }
+    if let Some(timestamp) = transaction.timestamp {
+        slot[39] = 1;
+        slot[40..48].copy_from_slice(&timestamp.to_le_bytes());
+    }
+    slot[48] = transaction.failed as u8;
+    slot
+}
+
+fn decode_slot(slot: &[u8; SLOT_SIZE as usize]) -> Option<(u16, InternalTransaction)> {
+    let kind = match slot[0] {
+        1 => TransactionType::Chargeback,
+        2 => TransactionType::Deposit,
+        3 => TransactionType::Dispute,
+        4 => TransactionType::Resolve,
+        5 => TransactionType::Withdrawal,
+        6 => TransactionType::Transfer,
+        7 => TransactionType::Freeze,
+        8 => TransactionType::Unlock,
+        _ => return None,
+    };
+    let client = u16::from_le_bytes([slot[1], slot[2]]);
+    let mut amount = [0u8; 16];
+    amount.copy_from_slice(&slot[3..19]);
+    let currency = Currency::from_bytes([slot[19], slot[20], slot[21]]);
+    let rate = match slot[22] {
+        0 => None,
+        _ => {
+            let mut rate = [0u8; 16];
+            rate.copy_from_slice(&slot[23..39]);
+            Some(Decimal::deserialize(rate))
+        }
+    };
+    let timestamp = match slot[39] {
+        0 => None,
+        _ => {
+            let mut timestamp = [0u8; 8];
+            timestamp.copy_from_slice(&slot[40..48]);
+            Some(u64::from_le_bytes(timestamp))
+        }
+    };
+    Some((
+        client,
+        InternalTransaction {
+            kind,
+            amount: Decimal::deserialize(amount),
+            currency,
+            rate,
+            timestamp,
+            failed: slot[48] != 0,
+        },
+    ))
+}
+
+// Least recently used cache. `order` maps a use counter to the key used at
+// that point, so the first entry is always the one to evict.
+#[derive(Debug)]
+struct LruCache {
+    capacity: usize,
+    entries: HashMap<(u16, u32), (InternalTransaction, u64)>,
+    order: BTreeMap<u64, (u16, u32)>,
+    counter: u64,
+}
+
+impl LruCache {
+    fn new(capacity: usize) -> LruCache {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            counter: 0,
+        }
+    }
+
+    fn get(&mut self, key: &(u16, u32)) -> Option<InternalTransaction> {
+        let counter = self.counter + 1;
+        let (transaction, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(counter, *key);
+        *last_used = counter;
+        self.counter = counter;
+        Some(*transaction)
+    }
+
+    fn insert(&mut self, key: (u16, u32), transaction: InternalTransaction) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.counter += 1;
+        if let Some((_, last_used)) = self.entries.insert(key, (transaction, self.counter)) {
+            self.order.remove(&last_used);
+        } else if self.entries.len() > self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.order.insert(self.counter, key);
+    }
+}
@@ -1,6 +1,13 @@
 #[cfg(test)]
 mod tests {
     use std::str::from_utf8;
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::model::ReadTransaction;
+    use crate::model::TransactionType::{self, *};
+    use crate::{Outcome, PaymentEngine, Rejection};
 
     #[test]
     fn should_error_on_bad_file() {
@@ -249,7 +256,7 @@ mod tests {
             Ok(_result) => {
                 assert_eq!(
                     from_utf8(&stdout).unwrap(),
-                    "client,available,held,total,locked\n1,1.500,1.2345,2.7345,false\n"
+                    "client,available,held,total,locked\n1,1.5000,1.2345,2.7345,false\n"
                 )
             }
             Err(_err) => {
@@ -276,4 +283,1523 @@ mod tests {
             }
         }
     }
+
+    fn transaction(
+        kind: TransactionType,
+        client: u16,
+        tx: u32,
+        amount: Option<&str>,
+    ) -> ReadTransaction {
+        ReadTransaction {
+            kind,
+            client,
+            tx,
+            amount: amount.map(|amount| Decimal::from_str(amount).unwrap()),
+            currency: None,
+            destination: None,
+            timestamp: None,
+            operator: None,
+        }
+    }
+
+    #[test]
+    fn should_apply_transactions_through_engine() {
+        let mut engine = PaymentEngine::new();
+        let deposit = transaction(Deposit, 1, 1, Some("1.2345"));
+
+        assert_eq!(engine.apply(deposit), Ok(Outcome::Deposited));
+        assert_eq!(engine.apply(deposit), Err(Rejection::DuplicateTx));
+
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.balance(None).available, Decimal::new(12345, 4));
+        assert_eq!(account.balance(None).total, Decimal::new(12345, 4));
+        assert!(engine.account(2).is_none());
+        assert_eq!(engine.accounts().count(), 1);
+    }
+
+    #[test]
+    fn should_report_rejection_reasons() {
+        let mut engine = PaymentEngine::new();
+
+        assert_eq!(
+            engine.apply(transaction(Deposit, 1, 1, None)),
+            Err(Rejection::MissingAmount)
+        );
+        assert_eq!(
+            engine.apply(transaction(Withdrawal, 1, 2, Some("1.0"))),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(
+            engine.apply(transaction(Dispute, 1, 3, None)),
+            Err(Rejection::UnknownReference)
+        );
+
+        engine
+            .apply(transaction(Deposit, 1, 4, Some("1.0")))
+            .unwrap();
+        assert_eq!(
+            engine.apply(transaction(Resolve, 1, 4, None)),
+            Err(Rejection::NotDisputed)
+        );
+        assert_eq!(
+            engine.apply(transaction(Chargeback, 1, 4, None)),
+            Err(Rejection::NotDisputed)
+        );
+        assert_eq!(
+            engine.apply(transaction(Dispute, 1, 4, None)),
+            Ok(Outcome::Disputed)
+        );
+        assert_eq!(
+            engine.apply(transaction(Dispute, 1, 4, None)),
+            Err(Rejection::AlreadyDisputed)
+        );
+        assert_eq!(
+            engine.apply(transaction(Resolve, 1, 4, None)),
+            Ok(Outcome::Resolved)
+        );
+        assert_eq!(
+            engine.apply(transaction(Dispute, 1, 4, None)),
+            Err(Rejection::DisputeSettled)
+        );
+
+        engine
+            .apply(transaction(Deposit, 1, 5, Some("1.0")))
+            .unwrap();
+        engine.apply(transaction(Dispute, 1, 5, None)).unwrap();
+        assert_eq!(
+            engine.apply(transaction(Chargeback, 1, 5, None)),
+            Ok(Outcome::ChargedBack)
+        );
+        assert_eq!(
+            engine.apply(transaction(Deposit, 1, 6, Some("1.0"))),
+            Err(Rejection::AccountLocked)
+        );
+    }
+
+    #[test]
+    fn should_write_rejected_rows_with_positions_and_reasons() {
+        let rejects_path = std::env::temp_dir().join("payment-engine-rejects-test.csv");
+        let options = crate::RunOptions {
+            inputs: vec![String::from("test-data/rejects.csv")],
+            rejects: Some(rejects_path.to_str().unwrap().to_string()),
+            ..crate::RunOptions::default()
+        };
+
+        let mut stdout = Vec::new();
+        crate::run(&options, &mut stdout).unwrap();
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n1,1.2345,0.0000,1.2345,false\n"
+        );
+
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "file,line,byte,reason,type,client,tx,amount,currency,destination,timestamp,operator\n\
+             test-data/rejects.csv,3,41,invalid_type,depost,hi,there,i am bad data.\n\
+             test-data/rejects.csv,4,72,insufficient_funds,withdrawal, 1, 2, 5.0\n\
+             test-data/rejects.csv,5,94,duplicate_tx,deposit,1,1,1.0\n\
+             test-data/rejects.csv,6,110,not_disputed,resolve,1,1\n\
+             test-data/rejects.csv,7,122,invalid_client,deposit,x,3,1.0\n"
+        );
+    }
+
+    #[test]
+    fn should_resume_from_snapshot() {
+        let snapshot_path = std::env::temp_dir().join("payment-engine-snapshot-test.json");
+        let snapshot = Some(snapshot_path.to_str().unwrap().to_string());
+        let _ = std::fs::remove_file(&snapshot_path);
+
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/simple-dispute.csv")],
+                snapshot: snapshot.clone(),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n1,0.0000,1.2345,1.2345,false\n"
+        );
+
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/resolve-after-snapshot.csv")],
+                snapshot,
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+
+        let output = from_utf8(&stdout).unwrap();
+        assert!(output.contains("\n1,1.2345,0.0000,1.2345,false\n"));
+        assert!(output.contains("\n2,3.0000,0.0000,3.0000,false\n"));
+    }
+
+    #[test]
+    fn should_refuse_unknown_snapshot_version() {
+        let snapshot = "{\"version\":999}\n";
+        assert!(PaymentEngine::new()
+            .read_snapshot(snapshot.as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn should_log_only_accepted_transactions_and_replay_them() {
+        let event_log_path = std::env::temp_dir().join("payment-engine-event-log-test.csv");
+        let event_log = event_log_path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&event_log_path);
+
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/rejects.csv")],
+                event_log: Some(event_log.clone()),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&event_log_path).unwrap(),
+            "type,client,tx,amount,currency,destination,timestamp,operator,input,position\n\
+             deposit,1,1,1.2345,,,,,test-data/rejects.csv,22\n"
+        );
+
+        let mut replayed = Vec::new();
+        crate::replay(&event_log, &crate::RunOptions::default(), &mut replayed).unwrap();
+        assert_eq!(from_utf8(&replayed).unwrap(), from_utf8(&stdout).unwrap());
+
+        // A second run recovers from the log before applying its own input.
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/resolve-after-snapshot.csv")],
+                event_log: Some(event_log.clone()),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&event_log_path).unwrap(),
+            "type,client,tx,amount,currency,destination,timestamp,operator,input,position\n\
+             deposit,1,1,1.2345,,,,,test-data/rejects.csv,22\n\
+             deposit,2,2,3.0,,,,,test-data/resolve-after-snapshot.csv,34\n"
+        );
+        std::fs::remove_file(&event_log_path).unwrap();
+
+        let output = from_utf8(&stdout).unwrap();
+        assert!(output.contains("\n1,1.2345,0.0000,1.2345,false\n"));
+        assert!(output.contains("\n2,3.0000,0.0000,3.0000,false\n"));
+    }
+
+    #[test]
+    fn should_resume_from_a_cut_event_log_with_the_same_arguments() {
+        let event_log_path = std::env::temp_dir().join(format!(
+            "payment-engine-event-log-restart-{}.csv",
+            std::process::id()
+        ));
+        let options = crate::RunOptions {
+            inputs: vec![String::from("test-data/event-log-restart.csv")],
+            event_log: Some(event_log_path.to_str().unwrap().to_string()),
+            ..crate::RunOptions::default()
+        };
+        let _ = std::fs::remove_file(&event_log_path);
+        let mut expected = Vec::new();
+        crate::run(&options, &mut expected).unwrap();
+        assert_eq!(
+            from_utf8(&expected).unwrap(),
+            "client,available,held,total,locked\n1,11.0000,0.0000,11.0000,false\n"
+        );
+        let complete = std::fs::read_to_string(&event_log_path).unwrap();
+
+        // The run crashed after logging transaction 2, or part way through
+        // logging transaction 3. The rejected withdrawal before them is not
+        // read again.
+        let after_tx_2 = complete.rfind("deposit,1,3").unwrap();
+        for cut in [after_tx_2, after_tx_2 + 5] {
+            std::fs::write(&event_log_path, &complete[..cut]).unwrap();
+            let mut stdout = Vec::new();
+            crate::run(&options, &mut stdout).unwrap();
+            assert_eq!(stdout, expected);
+            assert_eq!(std::fs::read_to_string(&event_log_path).unwrap(), complete);
+        }
+
+        // Running again after a complete run changes nothing either.
+        let mut stdout = Vec::new();
+        crate::run(&options, &mut stdout).unwrap();
+        std::fs::remove_file(&event_log_path).unwrap();
+        assert_eq!(stdout, expected);
+    }
+
+    #[test]
+    fn should_match_memory_store_with_disk_store() {
+        let store_path = std::env::temp_dir().join("payment-engine-disk-store-test.bin");
+
+        let mut expected = Vec::new();
+        crate::do_run("test-data/disk-store.csv", &mut expected).unwrap();
+
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/disk-store.csv")],
+                disk_store: Some(store_path.to_str().unwrap().to_string()),
+                cache_size: Some(1),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+        std::fs::remove_file(&store_path).unwrap();
+
+        assert_eq!(stdout, expected);
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n1,3.5000,0.5000,4.0000,false\n2,2.0000,0.0000,2.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn should_keep_reused_transaction_ids_apart_in_disk_store() {
+        use crate::model::InternalTransaction;
+        use crate::{DiskStore, TransactionStore};
+
+        let store_path = std::env::temp_dir().join("payment-engine-disk-store-collision-test.bin");
+        let mut store = DiskStore::create(store_path.to_str().unwrap(), 0).unwrap();
+        let first = InternalTransaction {
+            kind: Deposit,
+            amount: Decimal::new(1, 0),
+            currency: None,
+            rate: None,
+            timestamp: None,
+            failed: false,
+        };
+        let second = InternalTransaction {
+            kind: Withdrawal,
+            amount: Decimal::new(2, 0),
+            currency: Some("EUR".parse().unwrap()),
+            rate: Some(Decimal::new(11, 1)),
+            timestamp: Some(1_700_000_000),
+            failed: true,
+        };
+
+        store.insert(1, 7, first).unwrap();
+        store.insert(2, 7, second).unwrap();
+        assert_eq!(store.get(1, 7).unwrap(), Some(first));
+        assert_eq!(store.get(2, 7).unwrap(), Some(second));
+        assert_eq!(store.get(3, 7).unwrap(), None);
+        assert_eq!(store.get(1, 8).unwrap(), None);
+
+        let mut count = 0;
+        store
+            .for_each(&mut |_, _, _| {
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        std::fs::remove_file(&store_path).unwrap();
+    }
+
+    #[test]
+    fn should_match_sequential_run_when_sharded() {
+        let temp_dir = std::env::temp_dir();
+        for input in [
+            "test-data/many-clients.csv",
+            "test-data/disk-store.csv",
+            "test-data/rejects.csv",
+            "test-data/transfers.csv",
+            "test-data/tx-ids.csv",
+        ] {
+            let sequential_rejects = temp_dir.join("payment-engine-sequential-rejects-test.csv");
+            let sharded_rejects = temp_dir.join("payment-engine-sharded-rejects-test.csv");
+
+            let mut expected = Vec::new();
+            crate::run(
+                &crate::RunOptions {
+                    inputs: vec![String::from(input)],
+                    rejects: Some(sequential_rejects.to_str().unwrap().to_string()),
+                    ..crate::RunOptions::default()
+                },
+                &mut expected,
+            )
+            .unwrap();
+
+            let mut stdout = Vec::new();
+            crate::run(
+                &crate::RunOptions {
+                    inputs: vec![String::from(input)],
+                    rejects: Some(sharded_rejects.to_str().unwrap().to_string()),
+                    threads: Some(4),
+                    ..crate::RunOptions::default()
+                },
+                &mut stdout,
+            )
+            .unwrap();
+
+            assert_eq!(stdout, expected, "{}", input);
+            assert_eq!(
+                std::fs::read_to_string(&sharded_rejects).unwrap(),
+                std::fs::read_to_string(&sequential_rejects).unwrap(),
+                "{}",
+                input
+            );
+            std::fs::remove_file(&sequential_rejects).unwrap();
+            std::fs::remove_file(&sharded_rejects).unwrap();
+        }
+    }
+
+    #[test]
+    fn should_serve_transactions_over_tcp() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let _ = crate::serve(listener, PaymentEngine::new());
+        });
+
+        let send = |stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, line: &str| {
+            stream.write_all(line.as_bytes()).unwrap();
+            stream.write_all(b"\n").unwrap();
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            response
+        };
+
+        let mut first = TcpStream::connect(address).unwrap();
+        let mut first_reader = BufReader::new(first.try_clone().unwrap());
+        let mut second = TcpStream::connect(address).unwrap();
+        let mut second_reader = BufReader::new(second.try_clone().unwrap());
+
+        assert_eq!(
+            send(&mut first, &mut first_reader, "deposit,1,1,1.2345"),
+            "ok deposited\n"
+        );
+        assert_eq!(
+            send(&mut second, &mut second_reader, "deposit, 2, 2, 2.0"),
+            "ok deposited\n"
+        );
+        assert_eq!(
+            send(&mut second, &mut second_reader, "withdrawal,1,3,5.0"),
+            "rejected insufficient_funds\n"
+        );
+        assert_eq!(
+            send(&mut first, &mut first_reader, "depost,1,4,1.0"),
+            "rejected invalid_type\n"
+        );
+        assert_eq!(
+            send(&mut first, &mut first_reader, "dispute,1,1"),
+            "ok disputed\n"
+        );
+
+        second.write_all(b"balances\n").unwrap();
+        let mut balances = Vec::new();
+        loop {
+            let mut line = String::new();
+            second_reader.read_line(&mut line).unwrap();
+            if line == "\n" {
+                break;
+            }
+            balances.push(line);
+        }
+        assert_eq!(
+            balances,
+            vec![
+                "client,available,held,total,locked\n",
+                "1,0.0000,1.2345,1.2345,false\n",
+                "2,2.0000,0.0000,2.0000,false\n",
+            ]
+        );
+    }
+
+    fn http_request(address: std::net::SocketAddr, request: &str) -> (u16, String) {
+        use std::io::{Read, Write};
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap().to_string();
+        (status, body)
+    }
+
+    fn http_post(address: std::net::SocketAddr, path: &str, body: &str) -> (u16, String) {
+        http_request(
+            address,
+            &format!(
+                "POST {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                path,
+                body.len(),
+                body
+            ),
+        )
+    }
+
+    fn http_get(address: std::net::SocketAddr, path: &str) -> (u16, String) {
+        http_request(
+            address,
+            &format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            ),
+        )
+    }
+
+    #[test]
+    fn should_serve_transactions_over_http() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let _ = crate::serve_http(listener, PaymentEngine::new());
+        });
+
+        assert_eq!(
+            http_post(
+                address,
+                "/transactions",
+                r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.2345"}"#
+            ),
+            (
+                200,
+                String::from(r#"{"result":"accepted","outcome":"deposited"}"#)
+            )
+        );
+        assert_eq!(
+            http_post(
+                address,
+                "/transactions",
+                r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "5.0"}"#
+            ),
+            (
+                422,
+                String::from(r#"{"result":"rejected","reason":"insufficient_funds"}"#)
+            )
+        );
+        assert_eq!(
+            http_post(
+                address,
+                "/transactions",
+                r#"{"type": "dispute", "client": 1, "tx": 1}"#
+            ),
+            (
+                200,
+                String::from(r#"{"result":"accepted","outcome":"disputed"}"#)
+            )
+        );
+        assert_eq!(
+            http_post(address, "/transactions", r#"{"type": "depost"}"#).0,
+            400
+        );
+
+        assert_eq!(
+            http_get(address, "/accounts/1"),
+            (
+                200,
+                String::from(
+                    r#"[{"client":1,"available":"0.0000","held":"1.2345","total":"1.2345","locked":false,"disputes":[1]}]"#
+                )
+            )
+        );
+        assert_eq!(http_get(address, "/accounts/2").0, 404);
+        assert_eq!(http_get(address, "/accounts/nope").0, 400);
+        assert_eq!(http_get(address, "/nothing").0, 404);
+    }
+
+    #[test]
+    fn should_process_multiple_inputs_in_order() {
+        let rejects_path = std::env::temp_dir().join("payment-engine-multiple-inputs-test.csv");
+
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![
+                    String::from("test-data/simple-dispute.csv"),
+                    String::from("test-data/resolve-after-snapshot.csv"),
+                    String::from("test-data/single-withdrawal.csv"),
+                ],
+                rejects: Some(rejects_path.to_str().unwrap().to_string()),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+
+        let output = from_utf8(&stdout).unwrap();
+        assert!(output.contains("\n1,1.2345,0.0000,1.2345,false\n"));
+        assert!(output.contains("\n2,3.0000,0.0000,3.0000,false\n"));
+
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "file,line,byte,reason,type,client,tx,amount,currency,destination,timestamp,operator\n\
+             test-data/single-withdrawal.csv,2,22,duplicate_tx,withdrawal,1,1,1.2345\n"
+        );
+    }
+
+    fn run_with_format(input: &str, output_format: crate::OutputFormat, extended: bool) -> String {
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from(input)],
+                output_format,
+                extended,
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+        String::from_utf8(stdout).unwrap()
+    }
+
+    #[test]
+    fn should_write_json_ndjson_and_table_output() {
+        use crate::OutputFormat;
+
+        assert_eq!(
+            run_with_format("test-data/simple-dispute.csv", OutputFormat::Json, false),
+            "[{\"client\":1,\"available\":\"0.0000\",\"held\":\"1.2345\",\"total\":\"1.2345\",\"locked\":false}]\n"
+        );
+        assert_eq!(
+            run_with_format("test-data/simple-dispute.csv", OutputFormat::Ndjson, true),
+            "{\"client\":1,\"available\":\"0.0000\",\"held\":\"1.2345\",\"total\":\"1.2345\",\"locked\":false,\"disputes\":[1]}\n"
+        );
+        assert_eq!(
+            run_with_format("test-data/simple-dispute.csv", OutputFormat::Table, false),
+            "client  available  held    total   locked\n\
+             1       0.0000     1.2345  1.2345  false\n"
+        );
+        assert_eq!(
+            run_with_format("test-data/simple-dispute.csv", OutputFormat::Csv, false),
+            "client,available,held,total,locked\n1,0.0000,1.2345,1.2345,false\n"
+        );
+    }
+
+    #[test]
+    fn should_refuse_extended_csv_output() {
+        let mut stdout = Vec::new();
+        assert!(crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/simple-dispute.csv")],
+                extended: true,
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn should_write_accounts_sorted_by_client() {
+        let mut first = Vec::new();
+        crate::do_run("test-data/many-clients.csv", &mut first).unwrap();
+        let mut second = Vec::new();
+        crate::do_run("test-data/many-clients.csv", &mut second).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(
+            from_utf8(&first).unwrap(),
+            "client,available,held,total,locked\n\
+             1,3.5300,0.0000,3.5300,false\n\
+             2,2.3800,0.0000,2.3800,false\n\
+             3,2.0900,0.0000,2.0900,false\n\
+             4,15.0000,0.0000,15.0000,false\n\
+             5,5.3500,4.0500,9.4000,false\n\
+             6,11.3300,0.0000,11.3300,false\n"
+        );
+    }
+
+    #[test]
+    fn should_reject_or_round_over_precise_amounts() {
+        use crate::{Precision, Rounding};
+
+        let mut engine = PaymentEngine::new();
+        assert_eq!(
+            engine.apply(transaction(Deposit, 1, 1, Some("1.23456"))),
+            Err(Rejection::TooPrecise)
+        );
+        assert_eq!(
+            engine.apply(transaction(Deposit, 1, 2, Some("1.50000"))),
+            Ok(Outcome::Deposited)
+        );
+        assert_eq!(
+            engine
+                .account(1)
+                .unwrap()
+                .balance(None)
+                .available
+                .to_string(),
+            "1.5000"
+        );
+
+        let rounded = |rounding, amount| {
+            let mut engine = PaymentEngine::new();
+            engine.set_precision(Precision { scale: 2, rounding });
+            engine
+                .apply(transaction(Deposit, 1, 1, Some(amount)))
+                .unwrap();
+            engine
+                .account(1)
+                .unwrap()
+                .balance(None)
+                .available
+                .to_string()
+        };
+        assert_eq!(rounded(Rounding::Bankers, "1.125"), "1.12");
+        assert_eq!(rounded(Rounding::Bankers, "1.135"), "1.14");
+        assert_eq!(rounded(Rounding::HalfUp, "1.125"), "1.13");
+        assert_eq!(rounded(Rounding::Truncate, "1.129"), "1.12");
+        assert_eq!(rounded(Rounding::Truncate, "7"), "7.00");
+
+        // An amount that rounds to zero is no deposit at all, and leaves
+        // nothing to dispute.
+        for rounding in [Rounding::Truncate, Rounding::Bankers, Rounding::HalfUp] {
+            let mut engine = PaymentEngine::new();
+            engine.set_precision(Precision { scale: 2, rounding });
+            assert_eq!(
+                engine.apply(transaction(Deposit, 1, 1, Some("0.001"))),
+                Err(Rejection::ZeroAmount)
+            );
+            assert_eq!(
+                engine.apply(transaction(Dispute, 1, 1, None)),
+                Err(Rejection::UnknownReference)
+            );
+        }
+    }
+
+    #[test]
+    fn should_keep_precision_across_snapshots_and_replay() {
+        use crate::Precision;
+
+        let six_places = Precision {
+            scale: 6,
+            ..Precision::default()
+        };
+        let mut engine = PaymentEngine::new();
+        engine.set_precision(six_places);
+        engine
+            .apply(transaction(Deposit, 1, 1, Some("1.123456")))
+            .unwrap();
+        let mut snapshot = Vec::new();
+        engine.write_snapshot(&mut snapshot).unwrap();
+
+        // Loading at four places would have to round the balance.
+        assert!(PaymentEngine::new()
+            .read_snapshot(snapshot.as_slice())
+            .is_err());
+        let mut resumed = PaymentEngine::new();
+        resumed.set_precision(six_places);
+        resumed.read_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(
+            resumed
+                .account(1)
+                .unwrap()
+                .balance(None)
+                .available
+                .to_string(),
+            "1.123456"
+        );
+
+        let event_log_path = std::env::temp_dir().join(format!(
+            "payment-engine-precision-replay-{}.csv",
+            std::process::id()
+        ));
+        let event_log = event_log_path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&event_log_path);
+        let mut event_log_engine = PaymentEngine::new();
+        event_log_engine.set_precision(six_places);
+        event_log_engine.set_event_log(crate::EventLog::open(&event_log).unwrap());
+        event_log_engine
+            .apply(transaction(Deposit, 1, 1, Some("1.123456")))
+            .unwrap();
+        drop(event_log_engine);
+
+        let mut replayed = Vec::new();
+        crate::replay(
+            &event_log,
+            &crate::RunOptions {
+                precision: six_places,
+                ..crate::RunOptions::default()
+            },
+            &mut replayed,
+        )
+        .unwrap();
+        std::fs::remove_file(&event_log_path).unwrap();
+        assert_eq!(
+            from_utf8(&replayed).unwrap(),
+            "client,available,held,total,locked\n1,1.123456,0.000000,1.123456,false\n"
+        );
+    }
+
+    #[test]
+    fn should_write_empty_accounts_at_the_configured_precision() {
+        use crate::{OutputFormat, Precision};
+
+        // Client 1's withdrawal fails, so it has no balance to take the
+        // scale from.
+        let run_at_two_places = |output_format| {
+            let mut stdout = Vec::new();
+            crate::run(
+                &crate::RunOptions {
+                    inputs: vec![String::from("test-data/empty-account.csv")],
+                    precision: Precision {
+                        scale: 2,
+                        ..Precision::default()
+                    },
+                    output_format,
+                    ..crate::RunOptions::default()
+                },
+                &mut stdout,
+            )
+            .unwrap();
+            String::from_utf8(stdout).unwrap()
+        };
+        assert_eq!(
+            run_at_two_places(OutputFormat::Csv),
+            "client,available,held,total,locked
+\
+             1,0.00,0.00,0.00,false
+\
+             2,1.50,0.00,1.50,false
+"
+        );
+        assert_eq!(
+            run_at_two_places(OutputFormat::Json),
+            "[{\"client\":1,\"available\":\"0.00\",\"held\":\"0.00\",\"total\":\"0.00\",\"locked\":false},\
+             {\"client\":2,\"available\":\"1.50\",\"held\":\"0.00\",\"total\":\"1.50\",\"locked\":false}]
+"
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_amounts_with_distinct_reasons() {
+        let rejects_path = std::env::temp_dir().join(format!(
+            "payment-engine-invalid-amounts-{}.csv",
+            std::process::id()
+        ));
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/invalid-amounts.csv")],
+                rejects: Some(rejects_path.to_str().unwrap().to_string()),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n1,0.0000,2.0000,2.0000,false\n"
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        let reasons: Vec<&str> = rejects
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(3).unwrap())
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                "negative_amount",
+                "zero_amount",
+                "negative_amount",
+                "zero_amount",
+                "unexpected_amount",
+                "unexpected_amount"
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_transactions_that_overflow_balances() {
+        let mut engine = PaymentEngine::new();
+        let max = "79228162514264337593543950335";
+
+        engine.apply(transaction(Deposit, 1, 1, Some(max))).unwrap();
+        assert_eq!(
+            engine.apply(transaction(Deposit, 1, 2, Some("1"))),
+            Err(Rejection::BalanceOverflow)
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.balance(None).available, Decimal::MAX);
+        assert_eq!(account.balance(None).total, Decimal::MAX);
+
+        // Held funds count towards the total, so this overflows it even though
+        // the available balance would fit.
+        engine.apply(transaction(Deposit, 2, 3, Some(max))).unwrap();
+        engine.apply(transaction(Dispute, 2, 3, None)).unwrap();
+        assert_eq!(
+            engine.apply(transaction(Deposit, 2, 4, Some("1"))),
+            Err(Rejection::BalanceOverflow)
+        );
+        assert_eq!(
+            engine.apply(transaction(Dispute, 2, 4, None)),
+            Err(Rejection::UnknownReference)
+        );
+        let account = engine.account(2).unwrap();
+        assert_eq!(account.balance(None).available, Decimal::ZERO);
+        assert_eq!(account.balance(None).held, Decimal::MAX);
+    }
+
+    #[test]
+    fn should_keep_balances_per_currency() {
+        let mut stdout = Vec::new();
+        crate::do_run("test-data/multi-currency.csv", &mut stdout).unwrap();
+
+        // The EUR withdrawal fails against the EUR balance even though the client
+        // holds enough USD. Disputes hold funds in the currency of the deposit,
+        // not the one named on the dispute row.
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,currency,available,held,total,locked\n\
+             1,,1.0000,0.0000,1.0000,false\n\
+             1,EUR,0.0000,5.0000,5.0000,false\n\
+             1,USD,7.0000,0.0000,7.0000,false\n\
+             2,GBP,0.0000,0.0000,0.0000,true\n"
+        );
+    }
+
+    #[test]
+    fn should_convert_currencies_with_rate_table() {
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/conversion.csv")],
+                rates: Some(String::from("test-data/rates.csv")),
+                base_currency: Some("USD".parse().unwrap()),
+                spread: Some(Decimal::new(1, 2)),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+
+        // 10 EUR at 1.10 less 1% is 10.89, 4 GBP at 1.25 plus 1% is 5.05, USD is
+        // the base currency and JPY has no rate.
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n1,8.8400,0.0000,8.8400,false\n"
+        );
+
+        let mut rates = crate::RateTable::new();
+        rates.set_rate("EUR".parse().unwrap(), Decimal::new(11, 1));
+        let mut engine = PaymentEngine::new();
+        engine.set_rates(rates);
+        let mut deposit = transaction(Deposit, 1, 1, Some("10.00"));
+        deposit.currency = Some("EUR".parse().unwrap());
+        engine.apply(deposit).unwrap();
+        deposit.tx = 2;
+        deposit.currency = Some("GBP".parse().unwrap());
+        assert_eq!(engine.apply(deposit), Err(Rejection::UnknownRate));
+
+        let stored = engine.transactions.get(1, 1).unwrap().unwrap();
+        assert_eq!(stored.amount, Decimal::new(110000, 4));
+        assert_eq!(stored.currency, None);
+        assert_eq!(stored.rate, Some(Decimal::new(11, 1)));
+
+        engine.apply(transaction(Dispute, 1, 1, None)).unwrap();
+        let balance = engine.account(1).unwrap().balance(None);
+        assert_eq!(balance.available, Decimal::ZERO);
+        assert_eq!(balance.held, Decimal::new(11, 0));
+    }
+
+    #[test]
+    fn should_transfer_between_clients_atomically() {
+        let rejects_path = std::env::temp_dir().join(format!(
+            "payment-engine-transfers-{}.csv",
+            std::process::id()
+        ));
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/transfers.csv")],
+                rejects: Some(rejects_path.to_str().unwrap().to_string()),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+
+        // Client 5 never gets an account because the only transfer to it failed.
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n\
+             1,4.0000,0.0000,4.0000,false\n\
+             2,0.0000,0.0000,0.0000,false\n\
+             3,0.0000,0.0000,0.0000,true\n\
+             4,7.0000,0.0000,7.0000,false\n"
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        let reasons: Vec<&str> = rejects
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(3).unwrap())
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                "insufficient_funds",
+                "self_transfer",
+                "missing_destination",
+                "destination_locked",
+                "account_locked",
+                "not_disputable",
+                "duplicate_tx",
+                "insufficient_funds",
+                "invalid_amount",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_charge_fees_again_on_replay() {
+        let event_log_path = std::env::temp_dir().join(format!(
+            "payment-engine-fee-replay-{}.csv",
+            std::process::id()
+        ));
+        let event_log = event_log_path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&event_log_path);
+        let options = crate::RunOptions {
+            fees: Some(String::from("test-data/fees.csv")),
+            house_account: Some(9),
+            ..crate::RunOptions::default()
+        };
+
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/fee-charges.csv")],
+                event_log: Some(event_log.clone()),
+                ..options.clone()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+
+        let mut replayed = Vec::new();
+        crate::replay(&event_log, &options, &mut replayed).unwrap();
+        std::fs::remove_file(&event_log_path).unwrap();
+        assert_eq!(from_utf8(&replayed).unwrap(), from_utf8(&stdout).unwrap());
+        assert!(from_utf8(&replayed)
+            .unwrap()
+            .contains("\n9,6.9600,0.0000,6.9600,false\n"));
+    }
+
+    #[test]
+    fn should_charge_fees_to_house_account() {
+        let ledger_path = std::env::temp_dir().join(format!(
+            "payment-engine-fee-ledger-{}.csv",
+            std::process::id()
+        ));
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/fee-charges.csv")],
+                fees: Some(String::from("test-data/fees.csv")),
+                house_account: Some(9),
+                fee_ledger: Some(ledger_path.to_str().unwrap().to_string()),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+
+        // Transfer 3 is raised to the minimum fee. Transfer 4 has the funds but
+        // not its capped fee on top. The house account pays no fee on its own
+        // deposit.
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n\
+             1,83.1500,0.0000,83.1500,false\n\
+             2,2.8900,1.0000,3.8900,false\n\
+             9,6.9600,0.0000,6.9600,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&ledger_path).unwrap(),
+            "client,tx,type,fee,currency,house_account\n\
+             1,1,deposit,1.1000,,9\n\
+             1,2,withdrawal,0.5000,,9\n\
+             1,3,transfer,0.2500,,9\n\
+             2,5,deposit,0.1100,,9\n\
+             2,5,dispute,2.0000,,9\n"
+        );
+        std::fs::remove_file(&ledger_path).unwrap();
+
+        // Fees would be credited to the house account from every worker.
+        assert!(crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/fee-charges.csv")],
+                fees: Some(String::from("test-data/fees.csv")),
+                threads: Some(4),
+                ..crate::RunOptions::default()
+            },
+            &mut Vec::new(),
+        )
+        .is_err());
+
+        let mut fees = crate::FeeSchedule::new();
+        fees.set_fee(Withdrawal, Decimal::ONE, Decimal::ZERO, None, None);
+        let mut engine = PaymentEngine::new();
+        engine.set_fees(fees);
+        engine
+            .apply(transaction(Deposit, 1, 1, Some("2.0")))
+            .unwrap();
+        assert_eq!(
+            engine.apply(transaction(Withdrawal, 1, 2, Some("1.5"))),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert!(engine.account(0).is_none());
+
+        // A server never finishes a run, so each fee is on disk as soon as it
+        // is charged rather than when the ledger is flushed.
+        engine.set_fee_ledger(crate::FeeLedger::from_path(ledger_path.to_str().unwrap()).unwrap());
+        engine
+            .apply(transaction(Withdrawal, 1, 3, Some("0.5")))
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&ledger_path).unwrap(),
+            "client,tx,type,fee,currency,house_account\n\
+             1,3,withdrawal,1.0000,,0\n"
+        );
+        std::fs::remove_file(&ledger_path).unwrap();
+    }
+
+    #[test]
+    fn should_reject_out_of_order_and_expired_disputes() {
+        let rejects_path = std::env::temp_dir().join(format!(
+            "payment-engine-timestamps-{}.csv",
+            std::process::id()
+        ));
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/timestamps.csv")],
+                rejects: Some(rejects_path.to_str().unwrap().to_string()),
+                dispute_window: Some(30),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+
+        // Client 2's deposit has no timestamp, so its dispute cannot be too
+        // late. Client 3's dispute has none and counts as its latest time.
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n\
+             1,10.0000,5.0000,15.0000,false\n\
+             2,0.0000,2.0000,2.0000,false\n\
+             3,1.0000,4.0000,5.0000,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&rejects_path).unwrap(),
+            "file,line,byte,reason,type,client,tx,amount,currency,destination,timestamp,operator\n\
+             test-data/timestamps.csv,4,112,out_of_order,deposit,1,3,1.0,,,1701000000\n\
+             test-data/timestamps.csv,5,141,dispute_expired,dispute,1,1,,,,1703000000\n"
+        );
+        std::fs::remove_file(&rejects_path).unwrap();
+    }
+
+    #[test]
+    fn should_freeze_and_unlock_accounts_by_operator() {
+        let temp_dir = std::env::temp_dir();
+        let rejects_path = temp_dir.join(format!(
+            "payment-engine-operator-rejects-{}.csv",
+            std::process::id()
+        ));
+        let event_log_path = temp_dir.join(format!(
+            "payment-engine-operator-events-{}.csv",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&event_log_path);
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/operators.csv")],
+                rejects: Some(rejects_path.to_str().unwrap().to_string()),
+                event_log: Some(event_log_path.to_str().unwrap().to_string()),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n\
+             1,3.0000,0.0000,3.0000,true\n\
+             2,1.0000,0.0000,1.0000,false\n"
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        let reasons: Vec<&str> = rejects
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(3).unwrap())
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                "account_locked",
+                "not_locked",
+                "account_locked",
+                "missing_operator"
+            ]
+        );
+        let events = std::fs::read_to_string(&event_log_path).unwrap();
+        assert!(events.contains("unlock,1,3,,,,,42,test-data/operators.csv,"));
+        assert!(events.contains("freeze,2,10,,,,,7,test-data/operators.csv,"));
+        std::fs::remove_file(&rejects_path).unwrap();
+        std::fs::remove_file(&event_log_path).unwrap();
+
+        let mut engine = PaymentEngine::new();
+        assert_eq!(
+            engine.apply(transaction(Freeze, 1, 1, None)),
+            Err(Rejection::MissingOperator)
+        );
+        let mut freeze = transaction(Freeze, 1, 1, None);
+        freeze.operator = Some(7);
+        assert_eq!(engine.apply(freeze), Ok(Outcome::Frozen));
+        assert_eq!(engine.apply(freeze), Ok(Outcome::Frozen));
+    }
+
+    // Runs `options` with an event log, checks that replaying the log with the
+    // same options rebuilds the same balances, and returns them.
+    fn run_and_replay(name: &str, options: crate::RunOptions) -> String {
+        let event_log_path = std::env::temp_dir().join(format!(
+            "payment-engine-{}-replay-{}.csv",
+            name,
+            std::process::id()
+        ));
+        let event_log = event_log_path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&event_log_path);
+
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                event_log: Some(event_log.clone()),
+                ..options.clone()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+        let mut replayed = Vec::new();
+        crate::replay(&event_log, &options, &mut replayed).unwrap();
+        std::fs::remove_file(&event_log_path).unwrap();
+
+        assert_eq!(from_utf8(&replayed).unwrap(), from_utf8(&stdout).unwrap());
+        String::from_utf8(stdout).unwrap()
+    }
+
+    #[test]
+    fn should_settle_disputes_on_locked_accounts_by_policy() {
+        for (policy, expected) in [
+            (crate::LockedPolicy::Frozen, "1,2.0000,3.0000,5.0000,true\n"),
+            (
+                crate::LockedPolicy::SettleDisputes,
+                "1,5.0000,0.0000,5.0000,true\n",
+            ),
+            (
+                crate::LockedPolicy::Disputes,
+                "1,3.0000,2.0000,5.0000,true\n",
+            ),
+        ] {
+            let stdout = run_and_replay(
+                "locked-disputes",
+                crate::RunOptions {
+                    inputs: vec![String::from("test-data/locked-disputes.csv")],
+                    policy: crate::Policy {
+                        locked: policy,
+                        ..crate::Policy::default()
+                    },
+                    ..crate::RunOptions::default()
+                },
+            );
+            assert_eq!(
+                stdout,
+                format!("client,available,held,total,locked\n{}", expected),
+                "{:?}",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn should_apply_rules_from_policy_file() {
+        let run_with = |policy: crate::Policy| {
+            run_and_replay(
+                "policy-rules",
+                crate::RunOptions {
+                    inputs: vec![String::from("test-data/policy-rules.csv")],
+                    policy,
+                    ..crate::RunOptions::default()
+                },
+            )
+        };
+
+        assert_eq!(
+            run_with(crate::Policy {
+                tx_ids: crate::TxIdScope::Client,
+                ..crate::Policy::default()
+            }),
+            "client,available,held,total,locked\n\
+             1,5.0000,0.0000,5.0000,false\n\
+             2,3.0000,0.0000,3.0000,false\n"
+        );
+
+        // Transaction 1 is disputed a second time, disputing the failed
+        // withdrawal holds nothing, and client 2 cannot reuse transaction ID 1.
+        let policy = crate::Policy::from_path("test-data/policy.toml").unwrap();
+        assert_eq!(policy.locked, crate::LockedPolicy::SettleDisputes);
+        assert_eq!(
+            run_with(policy),
+            "client,available,held,total,locked\n1,0.0000,5.0000,5.0000,false\n"
+        );
+
+        let policy = crate::Policy::from_path("test-data/policy.json").unwrap();
+        assert_eq!(
+            policy,
+            crate::Policy {
+                locked: crate::LockedPolicy::Disputes,
+                dispute_window: Some(30),
+                ..crate::Policy::default()
+            }
+        );
+    }
+
+    #[test]
+    fn should_keep_transaction_ids_unique_across_clients() {
+        let rejects_path =
+            std::env::temp_dir().join(format!("payment-engine-tx-ids-{}.csv", std::process::id()));
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/tx-ids.csv")],
+                rejects: Some(rejects_path.to_str().unwrap().to_string()),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+
+        // The failed withdrawal gives transaction ID 4 back for client 2.
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n\
+             1,3.0000,0.0000,3.0000,false\n\
+             2,6.0000,0.0000,6.0000,false\n"
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        let reasons: Vec<&str> = rejects
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(3).unwrap())
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                "tx_id_in_use",
+                "foreign_reference",
+                "foreign_reference",
+                "duplicate_tx",
+                "unknown_reference",
+                "insufficient_funds"
+            ]
+        );
+        std::fs::remove_file(&rejects_path).unwrap();
+
+        assert!(Rejection::ForeignReference.is_flagged());
+        assert!(!Rejection::UnknownReference.is_flagged());
+
+        let mut engine = PaymentEngine::new();
+        engine
+            .apply(transaction(Deposit, 1, u32::MAX, Some("1.0")))
+            .unwrap();
+        assert_eq!(
+            engine.apply(transaction(Withdrawal, 2, u32::MAX, Some("1.0"))),
+            Err(Rejection::TxIdInUse)
+        );
+    }
+
+    #[test]
+    fn should_keep_transaction_ids_from_snapshot_when_sharded() {
+        let snapshot_path = std::env::temp_dir().join(format!(
+            "payment-engine-tx-ids-snapshot-{}.json",
+            std::process::id()
+        ));
+        let rejects_path = std::env::temp_dir().join(format!(
+            "payment-engine-tx-ids-snapshot-rejects-{}.csv",
+            std::process::id()
+        ));
+        let snapshot = Some(snapshot_path.to_str().unwrap().to_string());
+        let _ = std::fs::remove_file(&snapshot_path);
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/single-deposit.csv")],
+                snapshot: snapshot.clone(),
+                ..crate::RunOptions::default()
+            },
+            &mut Vec::new(),
+        )
+        .unwrap();
+
+        // Client 2 is on another worker than client 1, whose transaction 1
+        // only the snapshot knows about.
+        let mut stdout = Vec::new();
+        crate::run(
+            &crate::RunOptions {
+                inputs: vec![String::from("test-data/tx-ids-after-snapshot.csv")],
+                snapshot,
+                rejects: Some(rejects_path.to_str().unwrap().to_string()),
+                threads: Some(2),
+                ..crate::RunOptions::default()
+            },
+            &mut stdout,
+        )
+        .unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n\
+             1,1.2345,0.0000,1.2345,false\n\
+             2,1.0000,0.0000,1.0000,false\n"
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        let reasons: Vec<&str> = rejects
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(3).unwrap())
+            .collect();
+        assert_eq!(reasons, ["tx_id_in_use", "foreign_reference"]);
+    }
+
+    #[test]
+    fn should_settle_reused_transaction_ids_in_input_order_when_sharded() {
+        let temp_dir = std::env::temp_dir();
+        let input_path = temp_dir.join(format!(
+            "payment-engine-reused-tx-ids-{}.csv",
+            std::process::id()
+        ));
+        let input = input_path.to_str().unwrap().to_string();
+
+        // Clients on every worker keep reusing a small set of IDs, and some of
+        // the withdrawals fail and give their ID back.
+        let mut rows = String::from("type,client,tx,amount\n");
+        for row in 0..4000u32 {
+            let client = row * 7 % 64;
+            let tx = row * 13 % 257;
+            match row % 5 {
+                0 | 1 => rows.push_str(&format!("deposit,{},{},{}.5\n", client, tx, row % 9 + 1)),
+                2 => rows.push_str(&format!(
+                    "withdrawal,{},{},{}.0\n",
+                    client,
+                    tx,
+                    row % 31 + 1
+                )),
+                3 => rows.push_str(&format!("dispute,{},{}\n", client, tx)),
+                _ => rows.push_str(&format!("chargeback,{},{}\n", client, tx)),
+            }
+        }
+        std::fs::write(&input_path, rows).unwrap();
+
+        let run = |threads, rejects: &std::path::Path| {
+            let mut stdout = Vec::new();
+            crate::run(
+                &crate::RunOptions {
+                    inputs: vec![input.clone()],
+                    rejects: Some(rejects.to_str().unwrap().to_string()),
+                    threads,
+                    ..crate::RunOptions::default()
+                },
+                &mut stdout,
+            )
+            .unwrap();
+            let rejects_report = std::fs::read_to_string(rejects).unwrap();
+            std::fs::remove_file(rejects).unwrap();
+            (stdout, rejects_report)
+        };
+
+        let expected = run(None, &temp_dir.join("payment-engine-reused-sequential.csv"));
+        assert!(expected.1.contains(",tx_id_in_use,"));
+        assert!(expected.1.contains(",foreign_reference,"));
+        for _ in 0..3 {
+            assert_eq!(
+                run(Some(4), &temp_dir.join("payment-engine-reused-sharded.csv")),
+                expected
+            );
+        }
+        std::fs::remove_file(&input_path).unwrap();
+    }
+
+    #[test]
+    fn should_hold_a_provisional_credit_when_disputing_withdrawals() {
+        let mut stdout = Vec::new();
+        crate::do_run("test-data/withdrawal-disputes.csv", &mut stdout).unwrap();
+
+        // Client 1's dispute is resolved and client 2's charged back. Client 3
+        // cannot spend the credit held by an open dispute.
+        assert_eq!(
+            from_utf8(&stdout).unwrap(),
+            "client,available,held,total,locked\n\
+             1,6.0000,0.0000,6.0000,false\n\
+             2,10.0000,0.0000,10.0000,true\n\
+             3,0.0000,5.0000,5.0000,false\n"
+        );
+
+        let balance = |engine: &PaymentEngine| {
+            let balance = engine.account(1).unwrap().balance(None);
+            (balance.available, balance.held, balance.total)
+        };
+        let mut engine = PaymentEngine::new();
+        engine
+            .apply(transaction(Deposit, 1, 1, Some("10")))
+            .unwrap();
+        engine
+            .apply(transaction(Withdrawal, 1, 2, Some("4")))
+            .unwrap();
+
+        engine.apply(transaction(Dispute, 1, 2, None)).unwrap();
+        assert_eq!(
+            balance(&engine),
+            (Decimal::new(6, 0), Decimal::new(4, 0), Decimal::new(10, 0))
+        );
+        engine.apply(transaction(Resolve, 1, 2, None)).unwrap();
+        assert_eq!(
+            balance(&engine),
+            (Decimal::new(6, 0), Decimal::ZERO, Decimal::new(6, 0))
+        );
+        assert_eq!(
+            engine.apply(transaction(Dispute, 1, 2, None)),
+            Err(Rejection::DisputeSettled)
+        );
+
+        // A deposit still holds its own funds.
+        engine.apply(transaction(Dispute, 1, 1, None)).unwrap();
+        assert_eq!(
+            balance(&engine),
+            (Decimal::new(-4, 0), Decimal::new(10, 0), Decimal::new(6, 0))
+        );
+        engine.apply(transaction(Chargeback, 1, 1, None)).unwrap();
+        assert_eq!(
+            balance(&engine),
+            (Decimal::new(-4, 0), Decimal::ZERO, Decimal::new(-4, 0))
+        );
+        assert!(engine.account(1).unwrap().locked);
+    }
 }
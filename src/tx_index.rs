@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+// Each page covers 262,144 transaction IDs in 32 KiB.
+const PAGE_BITS: usize = 1 << 18;
+const WORDS_PER_PAGE: usize = PAGE_BITS / 64;
+const PAGES: usize = (u32::MAX as usize + 1) / PAGE_BITS;
+
+type Page = [AtomicU64; WORDS_PER_PAGE];
+
+/// Every transaction ID used by a deposit, withdrawal or transfer, whichever
+/// client it belongs to.
+///
+/// One bit per ID, in pages that are only allocated once an ID in them is
+/// used. A run with IDs packed together costs about an eighth of a byte per
+/// transaction, and the whole `u32` range at most 512 MiB. Bits are set
+/// atomically, so IDs can be claimed through a shared reference.
+#[derive(Debug)]
+pub(crate) struct TxIndex {
+    pages: Box<[OnceLock<Box<Page>>]>,
+}
+
+impl Default for TxIndex {
+    fn default() -> TxIndex {
+        TxIndex {
+            pages: (0..PAGES).map(|_| OnceLock::new()).collect(),
+        }
+    }
+}
+
+impl TxIndex {
+    pub(crate) fn new() -> TxIndex {
+        TxIndex::default()
+    }
+
+    pub(crate) fn contains(&self, tx: u32) -> bool {
+        let (page, word, bit) = locate(tx);
+        match self.pages[page].get() {
+            Some(page) => page[word].load(Ordering::Acquire) & bit != 0,
+            None => false,
+        }
+    }
+
+    /// Marks `tx` as used. Returns whether it already was.
+    pub(crate) fn insert(&self, tx: u32) -> bool {
+        let (page, word, bit) = locate(tx);
+        let page = self.pages[page].get_or_init(new_page);
+        page[word].fetch_or(bit, Ordering::AcqRel) & bit != 0
+    }
+
+    /// Marks every ID used in `other` as used here too.
+    pub(crate) fn merge(&self, other: &TxIndex) {
+        for (page, other_page) in self.pages.iter().zip(other.pages.iter()) {
+            if let Some(other_page) = other_page.get() {
+                let page = page.get_or_init(new_page);
+                for (word, other_word) in page.iter().zip(other_page.iter()) {
+                    word.fetch_or(other_word.load(Ordering::Acquire), Ordering::AcqRel);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn remove(&self, tx: u32) {
+        let (page, word, bit) = locate(tx);
+        if let Some(page) = self.pages[page].get() {
+            page[word].fetch_and(!bit, Ordering::AcqRel);
+        }
+    }
+}
+
+fn locate(tx: u32) -> (usize, usize, u64) {
+    let tx = tx as usize;
+    (tx / PAGE_BITS, tx % PAGE_BITS / 64, 1 << (tx % 64))
+}
+
+fn new_page() -> Box<Page> {
+    Box::new([const { AtomicU64::new(0) }; WORDS_PER_PAGE])
+}
@@ -0,0 +1,68 @@
+use rust_decimal::Decimal;
+
+use crate::engine::Rejection;
+use crate::model::*;
+
+/// Checks a transaction on its own, before it reaches any account. Catches
+/// rows that parse fine but make no sense: a negative deposit would be an
+/// unchecked withdrawal, and a zero deposit would leave a disputable record
+/// behind.
+pub(crate) fn validate(transaction: &ReadTransaction) -> Result<(), Rejection> {
+    match (transaction.kind, transaction.amount) {
+        (TransactionType::Transfer, _) if transaction.destination.is_none() => {
+            warn!(
+                "Rejecting transfer. Missing destination. Transaction ID: {}",
+                &transaction.tx
+            );
+            Err(Rejection::MissingDestination)
+        }
+        (TransactionType::Transfer, _) if transaction.destination == Some(transaction.client) => {
+            warn!(
+                "Rejecting transfer. Destination is the same client. Transaction ID: {}",
+                &transaction.tx
+            );
+            Err(Rejection::SelfTransfer)
+        }
+        (TransactionType::Freeze | TransactionType::Unlock, _)
+            if transaction.operator.is_none() =>
+        {
+            warn!(
+                "Rejecting transaction. Missing operator. Transaction ID: {}",
+                &transaction.tx
+            );
+            Err(Rejection::MissingOperator)
+        }
+        (
+            TransactionType::Deposit | TransactionType::Transfer | TransactionType::Withdrawal,
+            Some(amount),
+        ) => {
+            if amount < Decimal::ZERO {
+                warn!(
+                    "Rejecting transaction. Negative amount. Transaction ID: {}",
+                    &transaction.tx
+                );
+                return Err(Rejection::NegativeAmount);
+            }
+            if amount.is_zero() {
+                warn!(
+                    "Rejecting transaction. Zero amount. Transaction ID: {}",
+                    &transaction.tx
+                );
+                return Err(Rejection::ZeroAmount);
+            }
+            Ok(())
+        }
+        (
+            TransactionType::Deposit | TransactionType::Transfer | TransactionType::Withdrawal,
+            None,
+        ) => Ok(()),
+        (_, Some(_)) => {
+            warn!(
+                "Rejecting transaction. Amount given for a transaction that references another. Referenced Transaction ID: {}",
+                &transaction.tx
+            );
+            Err(Rejection::UnexpectedAmount)
+        }
+        (_, None) => Ok(()),
+    }
+}